@@ -0,0 +1,45 @@
+//! Precompresses the frontend when it is embedded into the binary.
+
+#[path = "src/compress.rs"]
+mod compress;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// The embedded assets worth compressing. index.html is left out since
+/// the server rewrites it at startup.
+const ASSETS: &[(&str, &str)] = &[
+    ("style.css", "static/style.css"),
+    ("main.js", "frontend/static/main.js"),
+    ("main_bg.wasm", "frontend/static/main_bg.wasm"),
+];
+
+fn main() {
+    // Naming what the script reads keeps Cargo from rerunning it on every
+    // change in the package, whether or not the frontend is embedded.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/compress.rs");
+    println!("cargo:rerun-if-changed=static");
+
+    if env::var_os("CARGO_FEATURE_EMBED").is_none() {
+        return;
+    }
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    for (name, path) in ASSETS {
+        println!("cargo:rerun-if-changed={}", path);
+
+        let bytes = fs::read(path)
+            .unwrap_or_else(|e| panic!("could not read {}, build the frontend first: {}", path, e));
+
+        let out = Path::new(&out_dir);
+        fs::write(out.join(format!("{}.gz", name)), compress::gzip(&bytes)).unwrap();
+        fs::write(
+            out.join(format!("{}.br", name)),
+            compress::brotli(&bytes, compress::BROTLI_BEST),
+        )
+        .unwrap();
+    }
+}
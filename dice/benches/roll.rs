@@ -0,0 +1,54 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::distributions::Distribution;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use dice::dice::{Dice, DiceRoller, StdDice, ToUniform};
+
+/// The roll from before keep/drop used heaps, which sorts every roll and
+/// drains the lowest, as a baseline.
+fn roll_sorted<R: Rng>(dice: &StdDice, rng: &mut R) -> i64 {
+    let uniform = dice.range().to_uniform();
+
+    let mut rolls = vec![];
+    for _ in 0..dice.count() {
+        rolls.push(uniform.sample(rng));
+    }
+
+    rolls.sort();
+    rolls.drain(..dice.drop() as usize);
+
+    dice.multiplier() * rolls.iter().sum::<i64>() + dice.modifier()
+}
+
+fn roll(c: &mut Criterion) {
+    let mut group = c.benchmark_group("roll");
+
+    let cases = [
+        ("100000d6", Dice::new(100_000, 1..=6, 1, 0, 0)),
+        ("100000d6s3", Dice::new(100_000, 1..=6, 1, 0, 3)),
+        ("100000d6s99997", Dice::new(100_000, 1..=6, 1, 0, 99_997)),
+        ("4d6s1", Dice::new(4, 1..=6, 1, 0, 1)),
+    ];
+
+    for (name, dice) in cases.iter() {
+        let mut roller = DiceRoller::from(ChaCha8Rng::seed_from_u64(0));
+        group.bench_function(*name, |b| b.iter(|| roller.roll_dice(black_box(dice))));
+
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        group.bench_function(format!("{}/sorted", name), |b| {
+            b.iter(|| roll_sorted(black_box(dice), &mut rng))
+        });
+    }
+
+    let dice = Dice::new(50, 1..=10, 1, 0, 0);
+    let mut roller = DiceRoller::from(ChaCha8Rng::seed_from_u64(0));
+    group.bench_function("1000x50d10", |b| {
+        b.iter(|| roller.roll_dice_times(black_box(&dice), 1000))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, roll);
+criterion_main!(benches);
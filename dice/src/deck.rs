@@ -0,0 +1,180 @@
+use rand::{seq::SliceRandom, Rng};
+use thiserror::Error;
+
+use std::convert::TryFrom;
+
+use crate::dice::{DiceRoller, RollResult, StdDice};
+
+/// The most cards a deck may hold. Decks hold every outcome of the dice,
+/// so this is reached quickly: 8d6 alone has 1,679,616.
+pub const MAX_DECK_SIZE: u64 = 1_000_000;
+
+/// Deals the outcomes of some dice without replacement.
+///
+/// A deck holds one card for every way the dice can land, so 2d6 is a
+/// deck of 36 cards with six 7s. Cards are dealt until the deck runs out,
+/// at which point it is reshuffled.
+#[derive(Debug, Clone)]
+pub struct DiceDeck {
+    cards: Vec<RollResult>,
+    /// How many cards have been dealt since the last shuffle.
+    dealt: usize,
+}
+
+impl DiceDeck {
+    pub fn new(dice: &StdDice) -> Result<Self, DeckError> {
+        let (low, high) = (*dice.range.start(), *dice.range.end());
+
+        if high < low {
+            return Err(DeckError::InvalidRange(low, high));
+        }
+
+        let count = u32::try_from(dice.count).map_err(|_| DeckError::InvalidCount(dice.count))?;
+        let size = high
+            .checked_sub(low)
+            .and_then(|sides| sides.checked_add(1))
+            .and_then(|sides| (sides as u64).checked_pow(count))
+            .filter(|&size| size <= MAX_DECK_SIZE)
+            .ok_or(DeckError::TooLarge)?;
+
+        let mut cards = Vec::with_capacity(size as usize);
+        let mut faces = vec![low; count as usize];
+        let mut scratch = faces.clone();
+
+        loop {
+            scratch.copy_from_slice(&faces);
+            cards.push(dice.score(&mut scratch));
+
+            // Advance the faces like an odometer, stopping once it wraps.
+            let mut wrapped = true;
+            for face in faces.iter_mut() {
+                if *face < high {
+                    *face += 1;
+                    wrapped = false;
+                    break;
+                }
+                *face = low;
+            }
+
+            if wrapped {
+                break;
+            }
+        }
+
+        Ok(DiceDeck {
+            dealt: cards.len(),
+            cards,
+        })
+    }
+
+    /// The number of cards in the deck.
+    pub fn len(&self) -> usize {
+        self.cards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cards.is_empty()
+    }
+
+    /// The number of cards left before the deck is reshuffled.
+    pub fn remaining(&self) -> usize {
+        self.cards.len() - self.dealt
+    }
+
+    pub fn shuffle_with_rng<R: Rng + ?Sized>(&mut self, rng: &mut R) {
+        self.cards.shuffle(rng);
+        self.dealt = 0;
+    }
+
+    pub fn deal_with_rng<R: Rng + ?Sized>(&mut self, rng: &mut R) -> RollResult {
+        if self.remaining() == 0 {
+            self.shuffle_with_rng(rng);
+        }
+
+        let card = self.cards[self.dealt];
+        self.dealt += 1;
+
+        card
+    }
+}
+
+impl<R: Rng> DiceRoller<R> {
+    pub fn deal(&mut self, deck: &mut DiceDeck) -> RollResult {
+        deck.deal_with_rng(&mut self.rng)
+    }
+
+    pub fn deal_times(&mut self, deck: &mut DiceDeck, times: i64) -> Vec<RollResult> {
+        (0..times).map(|_| self.deal(deck)).collect()
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DeckError {
+    #[error("Dice have no sides between {0} and {1}")]
+    InvalidRange(i64, i64),
+    #[error("Can't deal {0} dice")]
+    InvalidCount(i64),
+    #[error("Deck would hold more than {} cards", MAX_DECK_SIZE)]
+    TooLarge,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice::Dice;
+
+    #[test]
+    fn deck_new_2d6() {
+        let deck = DiceDeck::new(&Dice::new(2, 1..=6, 1, 0, 0)).unwrap();
+
+        assert_eq!(36, deck.len());
+        assert_eq!(6, deck.cards.iter().filter(|&&card| card == 7).count());
+        assert_eq!(1, deck.cards.iter().filter(|&&card| card == 2).count());
+    }
+
+    #[test]
+    fn deck_new_applies_drop() {
+        let deck = DiceDeck::new(&Dice::new(2, 1..=6, 2, 1, 1)).unwrap();
+
+        // Keeping the higher of 2d6, doubled, plus one.
+        assert_eq!(36, deck.len());
+        assert_eq!(1, deck.cards.iter().filter(|&&card| card == 3).count());
+        assert_eq!(11, deck.cards.iter().filter(|&&card| card == 13).count());
+    }
+
+    #[test]
+    fn deck_new_too_large() {
+        assert_eq!(
+            Err(DeckError::TooLarge),
+            DiceDeck::new(&Dice::new(8, 1..=6, 1, 0, 0)).map(|deck| deck.len())
+        );
+    }
+
+    #[test]
+    fn deck_new_invalid_count() {
+        assert_eq!(
+            Err(DeckError::InvalidCount(-1)),
+            DiceDeck::new(&Dice::new(-1, 1..=6, 1, 0, 0)).map(|deck| deck.len())
+        );
+        assert_eq!(
+            Err(DeckError::InvalidCount(1 << 32)),
+            DiceDeck::new(&Dice::new(1 << 32, 1..=1, 1, 0, 0)).map(|deck| deck.len())
+        );
+    }
+
+    #[test]
+    fn deck_deals_every_card_before_reshuffle() {
+        let mut roller = DiceRoller::default();
+        let mut deck = DiceDeck::new(&Dice::new(2, 1..=6, 1, 0, 0)).unwrap();
+
+        for _ in 0..3 {
+            let mut dealt = roller.deal_times(&mut deck, 36);
+            let mut cards = deck.cards.clone();
+            dealt.sort();
+            cards.sort();
+
+            assert_eq!(cards, dealt);
+            assert_eq!(0, deck.remaining());
+        }
+    }
+}
@@ -2,13 +2,30 @@ use rand::{
     distributions::uniform::SampleUniform,
     distributions::{Distribution, Uniform},
     rngs::ThreadRng,
-    Rng,
+    Rng, SeedableRng,
 };
+use rand_chacha::ChaCha20Rng;
+use serde_derive::{Deserialize, Serialize};
 
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::ops::{Bound, RangeBounds, RangeInclusive};
 
 pub type StdDice = Dice<RangeInclusive<i64>>;
 
+/// The result of a single roll of some dice.
+pub type RollResult = i64;
+
+/// A roll along with every die that made it up.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Breakdown {
+    /// Each die, in the order it was rolled.
+    pub faces: Vec<i64>,
+    /// The lowest faces, which were left out of the total.
+    pub dropped: Vec<i64>,
+    pub total: RollResult,
+}
+
 pub trait ToUniform<T>
 where
     T: SampleUniform,
@@ -52,7 +69,7 @@ where
 #[derive(Debug, Clone)]
 pub struct DiceRoller<T: Rng> {
     /// The kind of Rng generator to use for rolling the dice.
-    rng: T,
+    pub(crate) rng: T,
 }
 
 impl DiceRoller<ThreadRng> {
@@ -61,7 +78,19 @@ impl DiceRoller<ThreadRng> {
             rng: rand::thread_rng(),
         }
     }
+}
+
+impl DiceRoller<ChaCha20Rng> {
+    /// A roller whose rolls are wholly decided by the seed, so that anyone
+    /// with the seed can roll them again.
+    pub fn seeded(seed: [u8; 32]) -> Self {
+        DiceRoller {
+            rng: ChaCha20Rng::from_seed(seed),
+        }
+    }
+}
 
+impl<R: Rng> DiceRoller<R> {
     pub fn roll_dice<T: ToUniform<i64>>(&mut self, dice: &Dice<T>) -> i64 {
         dice.roll_with_rng(&mut self.rng)
     }
@@ -78,8 +107,20 @@ impl DiceRoller<ThreadRng> {
         self.roll_dice(&dice)
     }
 
+    pub fn roll_breakdown<T: ToUniform<i64>>(&mut self, dice: &Dice<T>) -> Breakdown {
+        dice.roll_breakdown_with_rng(&mut self.rng)
+    }
+
+    pub fn roll_breakdown_times<T: ToUniform<i64>>(
+        &mut self,
+        dice: &Dice<T>,
+        times: i64,
+    ) -> Vec<Breakdown> {
+        (0..times).map(|_| self.roll_breakdown(dice)).collect()
+    }
+
     pub fn roll_dice_times<T: ToUniform<i64>>(&mut self, dice: &Dice<T>, times: i64) -> Vec<i64> {
-        let mut rolls = vec![];
+        let mut rolls = Vec::with_capacity(times.max(0) as usize);
 
         for _ in 0..times {
             rolls.push(self.roll_dice(dice));
@@ -88,6 +129,20 @@ impl DiceRoller<ThreadRng> {
         rolls
     }
 
+    /// Returns an endless iterator of rolls of the dice.
+    pub fn iter<'a, T: ToUniform<i64>>(&'a mut self, dice: &'a Dice<T>) -> Rolls<'a, R, T> {
+        Rolls { roller: self, dice }
+    }
+
+    /// Turns the roller into an endless stream of rolls of the dice.
+    ///
+    /// The stream is always ready, so consumers that should not hog
+    /// their executor need to pace it themselves.
+    #[cfg(feature = "stream")]
+    pub fn into_stream<T: ToUniform<i64>>(self, dice: Dice<T>) -> RollStream<R, T> {
+        RollStream { roller: self, dice }
+    }
+
     pub fn roll_times<T: ToUniform<i64>>(
         &mut self,
         count: i64,
@@ -117,6 +172,50 @@ impl<T: Rng> From<T> for DiceRoller<T> {
     }
 }
 
+/// An endless iterator of rolls, created by `DiceRoller::iter`.
+#[derive(Debug)]
+pub struct Rolls<'a, R: Rng, T: ToUniform<i64>> {
+    roller: &'a mut DiceRoller<R>,
+    dice: &'a Dice<T>,
+}
+
+impl<'a, R: Rng, T: ToUniform<i64>> Iterator for Rolls<'a, R, T> {
+    type Item = RollResult;
+
+    fn next(&mut self) -> Option<RollResult> {
+        Some(self.roller.roll_dice(self.dice))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (usize::MAX, None)
+    }
+}
+
+/// An endless stream of rolls, created by `DiceRoller::into_stream`.
+#[cfg(feature = "stream")]
+#[derive(Debug)]
+pub struct RollStream<R: Rng, T: ToUniform<i64>> {
+    roller: DiceRoller<R>,
+    dice: Dice<T>,
+}
+
+#[cfg(feature = "stream")]
+impl<R: Rng + Unpin, T: ToUniform<i64> + Unpin> futures_core::Stream for RollStream<R, T> {
+    type Item = RollResult;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<RollResult>> {
+        let this = self.get_mut();
+        std::task::Poll::Ready(Some(this.roller.roll_dice(&this.dice)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (usize::MAX, None)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Dice<T: ToUniform<i64>> {
     /// The number of equivalently sided dice being rolled.
@@ -147,18 +246,108 @@ impl<T: ToUniform<i64>> Dice<T> {
         }
     }
 
+    pub fn count(&self) -> i64 {
+        self.count
+    }
+
+    pub fn range(&self) -> &T {
+        &self.range
+    }
+
+    pub fn multiplier(&self) -> i64 {
+        self.multiplier
+    }
+
+    pub fn modifier(&self) -> i64 {
+        self.modifier
+    }
+
+    pub fn drop(&self) -> i64 {
+        self.drop
+    }
+
+    /// Totals a given set of rolls, dropping the lowest and applying the
+    /// multiplier and modifier. Sorts the rolls in place.
+    pub(crate) fn score(&self, rolls: &mut [i64]) -> i64 {
+        let drop = (self.drop.max(0) as usize).min(rolls.len());
+
+        rolls.sort_unstable();
+
+        self.multiplier * rolls[drop..].iter().sum::<i64>() + self.modifier
+    }
+
+    /// Rolls the dice without storing every roll. When nothing is dropped
+    /// the rolls are summed as they are sampled; otherwise a heap holding
+    /// whichever is smaller of the dropped or kept rolls is maintained.
     pub fn roll_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> i64 {
         let uniform = self.range.to_uniform();
 
-        let mut rolls = vec![];
-        for _ in 0..self.count {
-            rolls.push(uniform.sample(rng));
-        }
+        let count = self.count.max(0) as usize;
+        let drop = (self.drop.max(0) as usize).min(count);
+        let keep = count - drop;
+
+        let sum = if drop == 0 {
+            (0..count).map(|_| uniform.sample(rng)).sum::<i64>()
+        } else if keep == 0 {
+            0
+        } else if drop <= keep {
+            // Max-heap of the lowest `drop` rolls, subtracted from the total.
+            let mut lowest = BinaryHeap::with_capacity(drop);
+            let mut total = 0;
+
+            for _ in 0..count {
+                let roll = uniform.sample(rng);
+                total += roll;
+
+                if lowest.len() < drop {
+                    lowest.push(roll);
+                } else if let Some(mut top) = lowest.peek_mut() {
+                    if roll < *top {
+                        *top = roll;
+                    }
+                }
+            }
+
+            total - lowest.iter().sum::<i64>()
+        } else {
+            // Min-heap of the highest `keep` rolls.
+            let mut highest = BinaryHeap::with_capacity(keep);
+
+            for _ in 0..count {
+                let roll = Reverse(uniform.sample(rng));
+
+                if highest.len() < keep {
+                    highest.push(roll);
+                } else if let Some(mut top) = highest.peek_mut() {
+                    if roll < *top {
+                        *top = roll;
+                    }
+                }
+            }
+
+            highest.iter().map(|Reverse(roll)| roll).sum::<i64>()
+        };
 
-        rolls.sort();
-        rolls.drain(..self.drop as usize);
+        self.multiplier * sum + self.modifier
+    }
+
+    /// Rolls the dice, keeping every die. Unlike `roll_with_rng` this
+    /// stores each roll, so it suits small rolls that are shown to people.
+    pub fn roll_breakdown_with_rng<R: Rng + ?Sized>(&self, rng: &mut R) -> Breakdown {
+        let uniform = self.range.to_uniform();
 
-        self.multiplier * rolls.iter().sum::<i64>() + self.modifier
+        let faces = (0..self.count.max(0))
+            .map(|_| uniform.sample(rng))
+            .collect::<Vec<_>>();
+        let mut sorted = faces.clone();
+        let total = self.score(&mut sorted);
+        let drop = (self.drop.max(0) as usize).min(sorted.len());
+
+        Breakdown {
+            faces,
+            dropped: sorted[..drop].to_vec(),
+            total,
+        }
     }
 }
 
@@ -229,12 +418,76 @@ mod tests {
         }
     }
 
+    /// The original roll, which sorts every roll and drains the lowest.
+    fn roll_sorted<R: Rng>(dice: &StdDice, rng: &mut R) -> i64 {
+        let uniform = dice.range.to_uniform();
+
+        let mut rolls = vec![];
+        for _ in 0..dice.count {
+            rolls.push(uniform.sample(rng));
+        }
+
+        rolls.sort();
+        rolls.drain(..dice.drop as usize);
+
+        dice.multiplier * rolls.iter().sum::<i64>() + dice.modifier
+    }
+
+    #[test]
+    fn dice_roll_with_rng_matches_sorted() {
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha8Rng;
+
+        for &(count, drop) in &[(10, 0), (10, 1), (10, 5), (10, 9), (10, 10), (1, 1), (0, 0)] {
+            let dice = Dice::new(count, 1..=20, 3, -2, drop);
+
+            for seed in 0..50 {
+                let mut rng_0 = ChaCha8Rng::seed_from_u64(seed);
+                let mut rng_1 = ChaCha8Rng::seed_from_u64(seed);
+
+                assert_eq!(
+                    roll_sorted(&dice, &mut rng_0),
+                    dice.roll_with_rng(&mut rng_1)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dice_roll_breakdown_with_rng() {
+        let mut rng = rand::thread_rng();
+        let dice = Dice::new(5, 1..=6, 2, 3, 2);
+
+        for _ in 0..100 {
+            let breakdown = dice.roll_breakdown_with_rng(&mut rng);
+            let kept = breakdown.faces.iter().sum::<i64>() - breakdown.dropped.iter().sum::<i64>();
+            let mut sorted = breakdown.faces.clone();
+            sorted.sort();
+
+            assert_eq!(5, breakdown.faces.len());
+            assert_eq!(&sorted[..2], &breakdown.dropped[..]);
+            assert_eq!(2 * kept + 3, breakdown.total);
+        }
+    }
+
     #[test]
     fn dice_roller_from_rng() {
         let _dice_roller_0 = DiceRoller::from(rand::rngs::OsRng);
         let _dice_roller_1 = DiceRoller::from(rand::thread_rng());
     }
 
+    #[test]
+    fn dice_roller_seeded() {
+        let dice = Dice::new(3, 1..=20, 1, 0, 1);
+
+        let rolls_0 = DiceRoller::seeded([7; 32]).roll_dice_times(&dice, 20);
+        let rolls_1 = DiceRoller::seeded([7; 32]).roll_dice_times(&dice, 20);
+        let rolls_2 = DiceRoller::seeded([8; 32]).roll_dice_times(&dice, 20);
+
+        assert_eq!(rolls_0, rolls_1);
+        assert_ne!(rolls_0, rolls_2);
+    }
+
     #[test]
     fn dice_roller_default() {
         let _dice_roller = DiceRoller::default();
@@ -256,6 +509,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn dice_roller_iter() {
+        let mut dice_roller = DiceRoller::default();
+        let dice = Dice::new(3, 1..=6, 1, 4, 0);
+
+        let rolls = dice_roller.iter(&dice).take(100).collect::<Vec<_>>();
+
+        assert_eq!(100, rolls.len());
+        for roll in rolls {
+            assert!(7 <= roll);
+            assert!(22 >= roll);
+        }
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn dice_roller_into_stream() {
+        use futures::executor::block_on;
+        use futures::stream::StreamExt;
+        use rand::SeedableRng;
+        use rand_chacha::ChaCha8Rng;
+
+        let dice = Dice::new(3, 1..=6, 1, 4, 0);
+
+        let streamed = block_on(
+            DiceRoller::from(ChaCha8Rng::seed_from_u64(3))
+                .into_stream(dice.clone())
+                .take(50)
+                .collect::<Vec<_>>(),
+        );
+        let iterated = DiceRoller::from(ChaCha8Rng::seed_from_u64(3))
+            .iter(&dice)
+            .take(50)
+            .collect::<Vec<_>>();
+
+        assert_eq!(iterated, streamed);
+    }
+
     #[test]
     fn dice_drop_exceeds_count() {
         let mut dice_roller = DiceRoller::default();
@@ -286,8 +577,8 @@ mod tests {
         assert_eq!(10, rolls.len());
         for roll in rolls {
             dbg!(roll);
-            assert!(5 <= roll);
-            assert!(11 >= roll);
+            assert!(7 <= roll);
+            assert!(22 >= roll);
         }
     }
 }
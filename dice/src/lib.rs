@@ -1,2 +1,5 @@
+pub mod deck;
 pub mod dice;
 pub mod parse;
+pub mod sim;
+pub mod tables;
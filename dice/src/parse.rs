@@ -3,6 +3,7 @@ use thiserror::Error;
 
 use std::fmt;
 use std::iter::Peekable;
+use std::ops::Range;
 use std::str::Chars;
 
 pub fn parse_str(input: &str) -> Result<(i64, StdDice), ParseError> {
@@ -44,19 +45,41 @@ impl fmt::Display for Token {
 #[derive(Debug, Clone)]
 pub(crate) struct Lexer<'a> {
     pub(self) source: Peekable<Chars<'a>>,
+    /// The byte offset of the next character in the source.
+    position: usize,
+    /// The byte offset at which the last token started.
+    start: usize,
 }
 
 impl<'a> Lexer<'a> {
     pub(crate) fn new(source: &'a str) -> Self {
         Lexer {
             source: source.chars().peekable(),
+            position: 0,
+            start: 0,
         }
     }
 
+    /// The byte range of the last token returned.
+    pub(crate) fn span(&self) -> Range<usize> {
+        self.start..self.position
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let character = self.source.next();
+
+        if let Some(c) = character {
+            self.position += c.len_utf8();
+        }
+
+        character
+    }
+
     /// Returns a None if it encounters an invalid token
     /// or the end of the source.
     pub(crate) fn next(&mut self) -> Result<Token, ParseError> {
-        let character = self.source.next();
+        self.start = self.position;
+        let character = self.advance();
 
         if character.is_none() {
             return Ok(Token::Eof);
@@ -76,19 +99,22 @@ impl<'a> Lexer<'a> {
             'x' => Ok(Token::Times),
             'd' => Ok(Token::Dice),
             's' => Ok(Token::Drop),
-            character if character.is_numeric() => {
+            character if character.is_ascii_digit() => {
                 let mut number = character.to_string();
                 while let Some(c) = self.source.peek() {
-                    if c.is_numeric() {
-                        number.push(self.source.next().unwrap());
+                    if c.is_ascii_digit() {
+                        number.push(self.advance().unwrap());
                     } else {
                         break;
                     }
                 }
 
-                Ok(Token::Number(number.parse().unwrap()))
+                number
+                    .parse()
+                    .map(Token::Number)
+                    .map_err(|_| ParseError::NumberTooLarge(number, self.span()))
             }
-            _ => Err(ParseError::InvalidToken(character.to_string())),
+            _ => Err(ParseError::InvalidToken(character.to_string(), self.span())),
         }
     }
 }
@@ -147,6 +173,7 @@ impl<'a> Parser<'a> {
             Err(ParseError::UnexpectedToken(
                 "Number(n)".to_string(),
                 self.current.to_string(),
+                self.lexer.span(),
             ))
         }
     }
@@ -200,6 +227,7 @@ impl<'a> Parser<'a> {
             Err(ParseError::UnexpectedToken(
                 expected.to_string(),
                 self.current.to_string(),
+                self.lexer.span(),
             ))
         }
     }
@@ -209,12 +237,25 @@ impl<'a> Parser<'a> {
     }
 }
 
+/// Each error carries the byte range of the source it was raised at.
 #[derive(Debug, Error, PartialEq)]
 pub enum ParseError {
     #[error("Encountered invalid token: `{0}`")]
-    InvalidToken(String),
+    InvalidToken(String, Range<usize>),
     #[error("Expected `{0}`, got `{1}`")]
-    UnexpectedToken(String, String),
+    UnexpectedToken(String, String, Range<usize>),
+    #[error("Number is too large: `{0}`")]
+    NumberTooLarge(String, Range<usize>),
+}
+
+impl ParseError {
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            ParseError::InvalidToken(_, span) => span.clone(),
+            ParseError::UnexpectedToken(_, _, span) => span.clone(),
+            ParseError::NumberTooLarge(_, span) => span.clone(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -315,6 +356,49 @@ mod tests {
         assert_eq!(Ok(Token::Eof), lexer.next());
     }
 
+    #[test]
+    fn lexer_span() {
+        let mut lexer = Lexer::new(" 40 d6");
+
+        assert_eq!(Ok(Token::Number(40)), lexer.next());
+        assert_eq!(1..3, lexer.span());
+        assert_eq!(Ok(Token::Dice), lexer.next());
+        assert_eq!(4..5, lexer.span());
+        assert_eq!(Ok(Token::Number(6)), lexer.next());
+        assert_eq!(Ok(Token::Eof), lexer.next());
+        assert_eq!(6..6, lexer.span());
+    }
+
+    #[test]
+    fn parse_error_span() {
+        assert_eq!(
+            ParseError::InvalidToken("q".to_string(), 3..4),
+            parse_str("1d6q").unwrap_err()
+        );
+        assert_eq!(
+            ParseError::UnexpectedToken("Dice".to_string(), "Add".to_string(), 2..3),
+            parse_str("3 +1").unwrap_err()
+        );
+        assert_eq!(4..4, parse_str("2x4d").unwrap_err().span());
+    }
+
+    #[test]
+    fn lexer_rejects_bad_numbers() {
+        assert_eq!(
+            ParseError::NumberTooLarge("99999999999999999999".to_string(), 0..20),
+            parse_str("99999999999999999999d6").unwrap_err()
+        );
+        assert_eq!(
+            Ok(Token::Number(i64::MAX)),
+            Lexer::new("9223372036854775807").next()
+        );
+        // Digits from other scripts aren't numbers to the lexer.
+        assert_eq!(
+            ParseError::InvalidToken("٣".to_string(), 0..2),
+            parse_str("٣d6").unwrap_err()
+        );
+    }
+
     #[test]
     fn parse_parse_str() {
         let input = "3x4d6*5+1s2";
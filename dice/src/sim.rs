@@ -0,0 +1,284 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+
+use crate::dice::{Dice, DiceRoller, StdDice, ToUniform};
+
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+/// The most work exact evaluation is allowed to do, measured in
+/// probability mass updates, before falling back on simulation.
+pub const EXACT_COST_LIMIT: u64 = 4_000_000;
+
+/// The z-score of a two-sided 95% confidence interval.
+const Z_95: f64 = 1.96;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simulation {
+    /// The number of trials that were rolled.
+    pub trials: u64,
+    /// How many times each result was rolled.
+    pub histogram: BTreeMap<i64, u64>,
+    /// The sample mean of the results.
+    pub mean: f64,
+    /// The sample standard deviation of the results.
+    pub std_dev: f64,
+    /// The 95% confidence interval of the mean.
+    pub confidence_interval: (f64, f64),
+}
+
+impl Simulation {
+    fn from_histogram(histogram: BTreeMap<i64, u64>) -> Self {
+        let trials = histogram.values().sum::<u64>();
+
+        if trials == 0 {
+            return Simulation {
+                trials,
+                histogram,
+                mean: 0.0,
+                std_dev: 0.0,
+                confidence_interval: (0.0, 0.0),
+            };
+        }
+
+        let n = trials as f64;
+        let mean = histogram
+            .iter()
+            .map(|(&value, &count)| value as f64 * count as f64)
+            .sum::<f64>()
+            / n;
+        let squares = histogram
+            .iter()
+            .map(|(&value, &count)| (value as f64 - mean).powi(2) * count as f64)
+            .sum::<f64>();
+        let std_dev = if trials > 1 {
+            (squares / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+
+        let margin = Z_95 * std_dev / n.sqrt();
+
+        Simulation {
+            trials,
+            histogram,
+            mean,
+            std_dev,
+            confidence_interval: (mean - margin, mean + margin),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exact {
+    /// The probability of rolling each possible result.
+    pub probabilities: BTreeMap<i64, f64>,
+    /// The expected value of a roll.
+    pub mean: f64,
+    /// The standard deviation of a roll.
+    pub std_dev: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Analysis {
+    Exact(Exact),
+    Simulated(Simulation),
+}
+
+impl Dice<RangeInclusive<i64>> {
+    /// Computes the distribution of the dice exactly by convolution.
+    ///
+    /// Returns None when the dice drop rolls, since the order statistics
+    /// that requires are not computed, when the convolution would cost
+    /// more than `EXACT_COST_LIMIT`, or when a result doesn't fit in an
+    /// `i64`.
+    pub fn exact(&self) -> Option<Exact> {
+        let (low, high) = (*self.range.start(), *self.range.end());
+
+        if self.drop != 0 || self.count < 0 || high < low {
+            return None;
+        }
+
+        let count = self.count as u64;
+        let sides = high.checked_sub(low)?.checked_add(1)? as u64;
+        let cost = count
+            .saturating_mul(count)
+            .saturating_mul(sides)
+            .saturating_mul(sides);
+
+        if cost > EXACT_COST_LIMIT {
+            return None;
+        }
+
+        // sums[i] is the probability that the dice sum to count * low + i.
+        let face = 1.0 / sides as f64;
+        let mut sums = vec![1.0];
+        for _ in 0..count {
+            let mut next = vec![0.0; sums.len() + sides as usize - 1];
+            for (i, p) in sums.iter().enumerate() {
+                for slot in &mut next[i..i + sides as usize] {
+                    *slot += p * face;
+                }
+            }
+            sums = next;
+        }
+
+        let mut probabilities = BTreeMap::new();
+        for (i, p) in sums.into_iter().enumerate() {
+            let value = self
+                .count
+                .checked_mul(low)?
+                .checked_add(i as i64)?
+                .checked_mul(self.multiplier)?
+                .checked_add(self.modifier)?;
+            *probabilities.entry(value).or_insert(0.0) += p;
+        }
+
+        let mean = probabilities
+            .iter()
+            .map(|(&value, p)| value as f64 * p)
+            .sum::<f64>();
+        let variance = probabilities
+            .iter()
+            .map(|(&value, p)| (value as f64 - mean).powi(2) * p)
+            .sum::<f64>();
+
+        Some(Exact {
+            probabilities,
+            mean,
+            std_dev: variance.sqrt(),
+        })
+    }
+}
+
+impl<R: Rng> DiceRoller<R> {
+    /// Rolls the dice `trials` times, split into `shares` run on the
+    /// current rayon thread pool.
+    ///
+    /// Each share gets its own generator, seeded from this roller, so
+    /// a seeded roller produces the same simulation every time.
+    pub fn simulate<T>(&mut self, dice: &Dice<T>, trials: u64, shares: usize) -> Simulation
+    where
+        T: ToUniform<i64> + Sync,
+    {
+        let shares = shares.max(1) as u64;
+        let seeds = (0..shares)
+            .map(|i| {
+                let share = trials / shares + if i < trials % shares { 1 } else { 0 };
+                (share, self.rng.gen::<u64>())
+            })
+            .collect::<Vec<_>>();
+
+        let histogram = seeds
+            .into_par_iter()
+            .map(|(share, seed)| {
+                let mut rng = ChaCha8Rng::seed_from_u64(seed);
+                let mut histogram = BTreeMap::new();
+
+                for _ in 0..share {
+                    *histogram.entry(dice.roll_with_rng(&mut rng)).or_insert(0) += 1;
+                }
+
+                histogram
+            })
+            .reduce(BTreeMap::new, |mut histogram, partial| {
+                for (value, count) in partial {
+                    *histogram.entry(value).or_insert(0) += count;
+                }
+                histogram
+            });
+
+        Simulation::from_histogram(histogram)
+    }
+
+    /// Evaluates the dice exactly when that is cheap enough, and
+    /// simulates them otherwise.
+    pub fn analyze(&mut self, dice: &StdDice, trials: u64, shares: usize) -> Analysis {
+        match dice.exact() {
+            Some(exact) => Analysis::Exact(exact),
+            None => Analysis::Simulated(self.simulate(dice, trials, shares)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_single_die() {
+        let dice = Dice::new(1, 1..=6, 1, 0, 0);
+        let exact = dice.exact().unwrap();
+
+        assert_eq!(6, exact.probabilities.len());
+        assert!((exact.mean - 3.5).abs() < 1e-9);
+        for p in exact.probabilities.values() {
+            assert!((p - 1.0 / 6.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn exact_applies_multiplier_and_modifier() {
+        let dice = Dice::new(2, 1..=6, 2, 3, 0);
+        let exact = dice.exact().unwrap();
+
+        assert_eq!(Some(&7), exact.probabilities.keys().next());
+        assert_eq!(Some(&27), exact.probabilities.keys().last());
+        assert!((exact.mean - 17.0).abs() < 1e-9);
+        assert!((exact.probabilities.values().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn exact_refuses_drop_and_large_dice() {
+        assert_eq!(None, Dice::new(4, 1..=6, 1, 0, 1).exact());
+        assert_eq!(None, Dice::new(1000, 1..=100, 1, 0, 0).exact());
+    }
+
+    #[test]
+    fn exact_refuses_overflow() {
+        assert_eq!(None, Dice::new(1, i64::MIN..=i64::MAX, 1, 0, 0).exact());
+        assert_eq!(None, Dice::new(1, 1..=6, i64::MAX, 1, 0).exact());
+    }
+
+    #[test]
+    fn simulate_counts_every_trial() {
+        let mut roller = DiceRoller::from(ChaCha8Rng::seed_from_u64(7));
+        let dice = Dice::new(3, 1..=6, 1, 0, 0);
+
+        let simulation = roller.simulate(&dice, 10_001, 4);
+
+        assert_eq!(10_001, simulation.trials);
+        assert_eq!(10_001, simulation.histogram.values().sum::<u64>());
+        assert!(*simulation.histogram.keys().next().unwrap() >= 3);
+        assert!(*simulation.histogram.keys().last().unwrap() <= 18);
+        assert!((simulation.mean - 10.5).abs() < 0.2);
+        assert!(simulation.confidence_interval.0 < simulation.mean);
+        assert!(simulation.confidence_interval.1 > simulation.mean);
+    }
+
+    #[test]
+    fn simulate_is_reproducible_with_seeded_roller() {
+        let dice = Dice::new(4, 1..=6, 1, 0, 1);
+
+        let first = DiceRoller::from(ChaCha8Rng::seed_from_u64(42)).simulate(&dice, 1000, 3);
+        let second = DiceRoller::from(ChaCha8Rng::seed_from_u64(42)).simulate(&dice, 1000, 3);
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn analyze_picks_method() {
+        let mut roller = DiceRoller::default();
+
+        match roller.analyze(&Dice::new(2, 1..=6, 1, 0, 0), 100, 1) {
+            Analysis::Exact(_) => {}
+            Analysis::Simulated(_) => panic!("2d6 should be evaluated exactly"),
+        }
+
+        match roller.analyze(&Dice::new(4, 1..=6, 1, 0, 1), 100, 1) {
+            Analysis::Simulated(simulation) => assert_eq!(100, simulation.trials),
+            Analysis::Exact(_) => panic!("4d6s1 should be simulated"),
+        }
+    }
+}
@@ -0,0 +1,516 @@
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::dice::{DiceRoller, StdDice};
+use crate::parse::{parse_str, ParseError};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// How many tables deep references may be followed before giving up,
+/// which keeps tables that refer to each other from looping forever.
+pub const MAX_DEPTH: usize = 8;
+
+/// Entries whose result starts with this refer to another table.
+const REFERENCE: char = '@';
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The lowest roll, inclusive, that lands on this entry.
+    pub low: i64,
+    /// The highest roll, inclusive, that lands on this entry.
+    pub high: i64,
+    /// The text of the entry, or `@name` to roll on the table `name`.
+    pub result: String,
+}
+
+impl Entry {
+    /// Returns the name of the table this entry refers to, if any.
+    pub fn reference(&self) -> Option<&str> {
+        if self.result.starts_with(REFERENCE) {
+            Some(self.result[1..].trim())
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub name: String,
+    /// The dice expression rolled to pick an entry.
+    pub roll: String,
+    dice: StdDice,
+    entries: Vec<Entry>,
+}
+
+/// The shape of TOML and JSON tables.
+#[derive(Debug, Deserialize)]
+struct RawTable {
+    roll: Option<String>,
+    entries: BTreeMap<String, String>,
+}
+
+impl Table {
+    /// Builds a table from `(range, result)` pairs, where a range is either
+    /// a single number or two numbers joined by a dash, such as `01-15`.
+    ///
+    /// If no roll is given, the table is rolled with a single die with as
+    /// many sides as the highest entry. The entries must leave no gaps, and
+    /// cover every result of the roll.
+    pub fn new<I>(name: &str, roll: Option<String>, entries: I) -> Result<Self, TableError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut entries = entries
+            .into_iter()
+            .map(|(range, result)| {
+                let (low, high) = parse_range(&range)?;
+                Ok(Entry {
+                    low,
+                    high,
+                    result: result.trim().to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>, TableError>>()?;
+
+        entries.sort_by_key(|entry| entry.low);
+
+        for pair in entries.windows(2) {
+            if pair[1].low <= pair[0].high {
+                return Err(TableError::Overlap(
+                    name.to_string(),
+                    pair[0].low,
+                    pair[0].high,
+                    pair[1].low,
+                ));
+            }
+            if pair[1].low > pair[0].high + 1 {
+                return Err(TableError::Gap(
+                    name.to_string(),
+                    pair[0].high + 1,
+                    pair[1].low - 1,
+                ));
+            }
+        }
+
+        let roll = match roll {
+            Some(roll) => roll,
+            None => match entries.last() {
+                Some(entry) => format!("1d{}", entry.high),
+                None => return Err(TableError::Empty(name.to_string())),
+            },
+        };
+
+        let dice = match parse_str(&roll) {
+            Ok((1, dice)) => dice,
+            Ok(_) => return Err(TableError::RepeatedRoll(name.to_string(), roll)),
+            Err(e) => return Err(TableError::InvalidRoll(name.to_string(), e)),
+        };
+
+        let (min, max) = match results(&dice) {
+            Some(results) => results,
+            None => return Err(TableError::Unrollable(name.to_string(), roll)),
+        };
+        // The entries are sorted and leave no gaps, so they cover one range.
+        let (low, high) = (entries[0].low, entries[entries.len() - 1].high);
+        if min < low || max > high {
+            let missed = if min < low { min } else { max };
+            return Err(TableError::Uncovered(name.to_string(), roll, missed));
+        }
+
+        Ok(Table {
+            name: name.to_string(),
+            roll,
+            dice,
+            entries,
+        })
+    }
+
+    pub fn from_toml(name: &str, source: &str) -> Result<Self, TableError> {
+        let raw: RawTable = toml::from_str(source)?;
+        Table::new(name, raw.roll, raw.entries)
+    }
+
+    pub fn from_json(name: &str, source: &str) -> Result<Self, TableError> {
+        let raw: RawTable = serde_json::from_str(source)?;
+        Table::new(name, raw.roll, raw.entries)
+    }
+
+    /// CSV tables are rows of `range,result`. A header row is skipped if
+    /// present, and the roll is always inferred from the entries.
+    pub fn from_csv(name: &str, source: &str) -> Result<Self, TableError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_reader(source.as_bytes());
+
+        let mut rows = vec![];
+        for (i, record) in reader.records().enumerate() {
+            let record = record?;
+            let range = record.get(0).unwrap_or_default().to_string();
+            let result = record.get(1).unwrap_or_default().to_string();
+
+            if i == 0 && parse_range(&range).is_err() {
+                continue;
+            }
+
+            rows.push((range, result));
+        }
+
+        Table::new(name, None, rows)
+    }
+
+    /// Loads a table from a `.toml`, `.json` or `.csv` file, named after
+    /// the file without its extension.
+    pub fn load(path: &Path) -> Result<Self, TableError> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| TableError::UnknownFormat(path.display().to_string()))?;
+        let source = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Table::from_toml(name, &source),
+            Some("json") => Table::from_json(name, &source),
+            Some("csv") => Table::from_csv(name, &source),
+            _ => Err(TableError::UnknownFormat(path.display().to_string())),
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Returns the entry a roll lands on, if any.
+    pub fn lookup(&self, roll: i64) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.low <= roll && roll <= entry.high)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TableRoll {
+    pub table: String,
+    pub roll: i64,
+    pub result: String,
+    /// The roll on the table this entry referred to, if it did.
+    pub nested: Option<Box<TableRoll>>,
+}
+
+impl TableRoll {
+    /// Returns the result at the end of the chain of references.
+    pub fn outcome(&self) -> &str {
+        match &self.nested {
+            Some(nested) => nested.outcome(),
+            None => &self.result,
+        }
+    }
+}
+
+/// A set of tables, which may refer to each other by name.
+#[derive(Debug, Clone, Default)]
+pub struct Tables {
+    tables: HashMap<String, Table>,
+}
+
+impl Tables {
+    pub fn new() -> Self {
+        Tables::default()
+    }
+
+    /// Loads every table in a directory, ignoring files of other formats.
+    pub fn load_dir(path: &Path) -> Result<Self, TableError> {
+        let mut tables = Tables::new();
+
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            let known = match path.extension().and_then(|ext| ext.to_str()) {
+                Some(ext) => ["toml", "json", "csv"].contains(&ext),
+                None => false,
+            };
+
+            if path.is_file() && known {
+                tables.insert(Table::load(&path)?);
+            }
+        }
+
+        tables.check_references()?;
+
+        Ok(tables)
+    }
+
+    /// Checks that every table referred to exists, so that a missing one
+    /// is found when the tables are loaded rather than when rolled.
+    pub fn check_references(&self) -> Result<(), TableError> {
+        for table in self.tables.values() {
+            for reference in table.entries.iter().filter_map(Entry::reference) {
+                if !self.tables.contains_key(reference) {
+                    return Err(TableError::UnknownReference(
+                        table.name.clone(),
+                        reference.to_string(),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn insert(&mut self, table: Table) {
+        self.tables.insert(table.name.clone(), table);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Table> {
+        self.tables.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /// Rolls on the named table, following references to other tables.
+    pub fn roll<R: Rng>(
+        &self,
+        name: &str,
+        roller: &mut DiceRoller<R>,
+    ) -> Result<TableRoll, TableError> {
+        self.roll_depth(name, roller, 0)
+    }
+
+    fn roll_depth<R: Rng>(
+        &self,
+        name: &str,
+        roller: &mut DiceRoller<R>,
+        depth: usize,
+    ) -> Result<TableRoll, TableError> {
+        if depth >= MAX_DEPTH {
+            return Err(TableError::TooDeep(name.to_string()));
+        }
+
+        let table = self
+            .get(name)
+            .ok_or_else(|| TableError::UnknownTable(name.to_string()))?;
+
+        let roll = roller.roll_dice(&table.dice);
+        let entry = table
+            .lookup(roll)
+            .ok_or_else(|| TableError::NoEntry(name.to_string(), roll))?;
+
+        let nested = match entry.reference() {
+            Some(reference) => Some(Box::new(self.roll_depth(reference, roller, depth + 1)?)),
+            None => None,
+        };
+
+        Ok(TableRoll {
+            table: name.to_string(),
+            roll,
+            result: entry.result.clone(),
+            nested,
+        })
+    }
+}
+
+/// The lowest and highest results the dice can roll, or None if they have
+/// no sides or the results don't fit in an `i64`.
+fn results(dice: &StdDice) -> Option<(i64, i64)> {
+    let (low, high) = (*dice.range.start(), *dice.range.end());
+    if high < low {
+        return None;
+    }
+
+    let kept = dice.count.checked_sub(dice.drop)?;
+    let ends = [kept.checked_mul(low)?, kept.checked_mul(high)?];
+    let ends = [
+        ends[0].checked_mul(dice.multiplier)?.checked_add(dice.modifier)?,
+        ends[1].checked_mul(dice.multiplier)?.checked_add(dice.modifier)?,
+    ];
+
+    Some((ends[0].min(ends[1]), ends[0].max(ends[1])))
+}
+
+fn parse_range(range: &str) -> Result<(i64, i64), TableError> {
+    let invalid = || TableError::InvalidRange(range.to_string());
+    let mut bounds = range.splitn(2, '-');
+
+    let low = bounds
+        .next()
+        .and_then(|low| low.trim().parse::<i64>().ok())
+        .ok_or_else(invalid)?;
+    let high = match bounds.next() {
+        Some(high) => high.trim().parse::<i64>().map_err(|_| invalid())?,
+        None => low,
+    };
+
+    if high < low {
+        return Err(invalid());
+    }
+
+    Ok((low, high))
+}
+
+#[derive(Debug, Error)]
+pub enum TableError {
+    #[error("Could not read table: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid TOML table: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Invalid JSON table: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid CSV table: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Unknown table format: `{0}`")]
+    UnknownFormat(String),
+    #[error("Invalid range: `{0}`")]
+    InvalidRange(String),
+    #[error("Table `{0}` has overlapping entries {1}-{2} and {3}")]
+    Overlap(String, i64, i64, i64),
+    #[error("Table `{0}` has no entries for rolls {1}-{2}")]
+    Gap(String, i64, i64),
+    #[error("Table `{0}` has no entries")]
+    Empty(String),
+    #[error("Table `{0}` can't roll `{1}`")]
+    Unrollable(String, String),
+    #[error("Table `{0}` has no entry for a roll of {2} on `{1}`")]
+    Uncovered(String, String, i64),
+    #[error("Table `{0}` refers to `{1}`, which doesn't exist")]
+    UnknownReference(String, String),
+    #[error("Table `{0}` has an invalid roll: {1}")]
+    InvalidRoll(String, ParseError),
+    #[error("Table `{0}` must roll once, not `{1}`")]
+    RepeatedRoll(String, String),
+    #[error("No such table: `{0}`")]
+    UnknownTable(String),
+    #[error("Table `{0}` has no entry for a roll of {1}")]
+    NoEntry(String, i64),
+    #[error("Table `{0}` is referred to too deeply")]
+    TooDeep(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(range, result)| (range.to_string(), result.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_range_forms() {
+        assert_eq!((1, 15), parse_range("01-15").unwrap());
+        assert_eq!((7, 7), parse_range("7").unwrap());
+        assert_eq!((3, 4), parse_range(" 3 - 4 ").unwrap());
+        assert!(parse_range("9-2").is_err());
+        assert!(parse_range("goblins").is_err());
+    }
+
+    #[test]
+    fn table_infers_roll() {
+        let table = Table::new("t", None, entries(&[("01-15", "a"), ("16-20", "b")])).unwrap();
+
+        assert_eq!("1d20", table.roll);
+        assert_eq!("a", table.lookup(1).unwrap().result);
+        assert_eq!("b", table.lookup(20).unwrap().result);
+        assert_eq!(None, table.lookup(21));
+    }
+
+    #[test]
+    fn table_rejects_overlap() {
+        let result = Table::new("t", None, entries(&[("1-10", "a"), ("10-20", "b")]));
+
+        assert!(matches!(result, Err(TableError::Overlap(_, 1, 10, 10))));
+    }
+
+    #[test]
+    fn table_rejects_gaps_and_uncovered_rolls() {
+        let result = Table::new("t", None, entries(&[("1-5", "a"), ("8-12", "b")]));
+        assert!(matches!(result, Err(TableError::Gap(_, 6, 7))));
+
+        let result = Table::new("t", None, entries(&[("3-6", "a")]));
+        assert!(matches!(result, Err(TableError::Uncovered(_, _, 1))));
+
+        let result = Table::new("t", Some("2d6".into()), entries(&[("2-11", "a")]));
+        assert!(matches!(result, Err(TableError::Uncovered(_, _, 12))));
+
+        let result = Table::new("t", None, entries(&[("0", "a")]));
+        assert!(matches!(result, Err(TableError::Unrollable(_, _))));
+    }
+
+    #[test]
+    fn tables_check_references() {
+        let mut tables = Tables::new();
+        tables.insert(Table::new("loot", None, entries(&[("1-6", "@gems")])).unwrap());
+
+        assert!(matches!(
+            tables.check_references(),
+            Err(TableError::UnknownReference(_, _))
+        ));
+
+        tables.insert(Table::new("gems", None, entries(&[("1-4", "Ruby")])).unwrap());
+        assert!(tables.check_references().is_ok());
+    }
+
+    #[test]
+    fn table_formats() {
+        let toml = Table::from_toml(
+            "t",
+            "roll = \"2d6\"\n[entries]\n\"2-6\" = \"Goblins\"\n\"7-12\" = \"Orcs\"\n",
+        )
+        .unwrap();
+        let json = Table::from_json(
+            "t",
+            r#"{"roll": "2d6", "entries": {"2-6": "Goblins", "7-12": "Orcs"}}"#,
+        )
+        .unwrap();
+        // Without a roll, the entries have to cover a single die from 1.
+        let csv = Table::from_csv("t", "range,result\n1-6,Goblins\n7-12,Orcs\n").unwrap();
+
+        assert_eq!("2d6", toml.roll);
+        assert_eq!(toml.entries(), json.entries());
+        assert_eq!(toml.entries()[1], csv.entries()[1]);
+        assert_eq!("1d12", csv.roll);
+        assert!(Table::from_csv("t", "range,result\n2-6,Goblins\n7-12,Orcs\n").is_err());
+    }
+
+    #[test]
+    fn tables_roll_nested() {
+        let mut tables = Tables::new();
+        tables.insert(Table::new("loot", None, entries(&[("1-6", "@gems")])).unwrap());
+        tables.insert(Table::new("gems", None, entries(&[("1-4", "Ruby")])).unwrap());
+
+        let mut roller = DiceRoller::default();
+        let roll = tables.roll("loot", &mut roller).unwrap();
+
+        assert_eq!("@gems", roll.result);
+        assert_eq!("gems", roll.nested.as_ref().unwrap().table);
+        assert_eq!("Ruby", roll.outcome());
+    }
+
+    #[test]
+    fn tables_roll_cycle() {
+        let mut tables = Tables::new();
+        tables.insert(Table::new("a", None, entries(&[("1", "@b")])).unwrap());
+        tables.insert(Table::new("b", None, entries(&[("1", "@a")])).unwrap());
+
+        let mut roller = DiceRoller::default();
+
+        assert!(matches!(
+            tables.roll("a", &mut roller),
+            Err(TableError::TooDeep(_))
+        ));
+        assert!(matches!(
+            tables.roll("c", &mut roller),
+            Err(TableError::UnknownTable(_))
+        ));
+    }
+}
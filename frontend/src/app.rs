@@ -1,16 +1,268 @@
+use anyhow::Error;
+use http::request::Request;
+use http::response::Response;
 use serde_derive::{Deserialize, Serialize};
-use yew::format::Json;
+use serde_json::json;
+use yew::format::{Json, Nothing, Text};
 use yew::prelude::*;
+use yew::services::fetch::{FetchService, FetchTask};
 use yew::services::storage::{Area, StorageService};
 
 use crate::die::Die;
 
 const KEY: &str = "state";
+const REQUEST_FAILED: &str = "The request failed. Try again later.";
 
 pub struct App {
     link: ComponentLink<Self>,
     storage: StorageService,
     state: State,
+    /// The set a share link opened, if any.
+    shared: Option<Shared>,
+    fetch_task: Option<FetchTask>,
+    account: Account,
+    username: String,
+    password: String,
+    /// Told to the user about their account, such as a failed login or a
+    /// conflicting save.
+    notice: Option<String>,
+    /// Account requests are kept apart from rolls, so that neither
+    /// cancels the other.
+    account_task: Option<FetchTask>,
+}
+
+/// Where the dice are kept besides the browser.
+#[derive(Clone, Copy, PartialEq)]
+enum Account {
+    /// Not logged in, so the dice are only kept in the browser.
+    LoggedOut,
+    /// Logged in, with the version of the saved dice last loaded or saved.
+    LoggedIn { version: u64 },
+}
+
+/// A dice set opened from a `/s/{id}` share link, shown read-only.
+enum Shared {
+    Loading,
+    Loaded(SetData),
+    Missing,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorData {
+    pub message: String,
+}
+
+/// The outcome of one roll of a batch.
+#[derive(Debug, Deserialize)]
+struct BatchData {
+    /// The name of the die rolled.
+    pub name: String,
+    pub roll: Option<Vec<i64>>,
+    pub error: Option<ErrorData>,
+}
+
+/// Rolls every die in one request, rather than one request per die.
+fn send_batch_request(app: &mut App) {
+    let post_request = Request::post("/dice/batch")
+        .header("Content-Type", "application/json")
+        .body(Json(&app.state.dice))
+        .expect("Failed to build post request.");
+
+    let task = FetchService::fetch(
+        post_request,
+        app.link
+            .callback(|response: Response<Json<Result<Vec<BatchData>, Error>>>| {
+                if let (meta, Json(Ok(body))) = response.into_parts() {
+                    if meta.status.is_success() {
+                        let outputs = body
+                            .into_iter()
+                            .map(|data| {
+                                let output = match (data.roll, data.error) {
+                                    (Some(roll), _) => format!("{:?}", roll),
+                                    (None, Some(error)) => error.message,
+                                    (None, None) => "Invalid input".to_string(),
+                                };
+                                (data.name, output)
+                            })
+                            .collect();
+
+                        return Msg::Outputs(outputs);
+                    }
+                }
+                Msg::FetchFailed
+            }),
+    );
+
+    if let Ok(t) = task {
+        app.fetch_task = Some(t)
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct SetDieData {
+    pub name: String,
+    pub roll: String,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct SetData {
+    pub name: String,
+    pub owner: String,
+    pub dice: Vec<SetDieData>,
+}
+
+/// Loads the set a share link points to.
+fn send_set_request(app: &mut App, id: &str) {
+    let get_request = Request::get(format!("/sets/{}", id))
+        .body(Nothing)
+        .expect("Failed to build get request.");
+
+    let task = FetchService::fetch(
+        get_request,
+        app.link
+            .callback(|response: Response<Json<Result<SetData, Error>>>| {
+                if let (meta, Json(Ok(body))) = response.into_parts() {
+                    if meta.status.is_success() {
+                        return Msg::SharedSet(Some(body));
+                    }
+                }
+                Msg::SharedSet(None)
+            }),
+    );
+
+    if let Ok(t) = task {
+        app.fetch_task = Some(t)
+    }
+}
+
+/// A user's saved dice, as `/me/dice` returns them.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedDiceData {
+    pub version: u64,
+    pub dice: Vec<DieData>,
+}
+
+/// The result of saving dice to `/me/dice`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedData {
+    pub version: u64,
+    /// Whether another device saved since the dice were last loaded.
+    pub conflict: bool,
+}
+
+/// A response's body, or the message of the error it carries.
+fn read_response(response: Response<Text>) -> Result<String, String> {
+    let (meta, body) = response.into_parts();
+    let body = body.map_err(|_| REQUEST_FAILED.to_string())?;
+
+    if meta.status.is_success() {
+        Ok(body)
+    } else {
+        Err(serde_json::from_str::<ErrorData>(&body)
+            .map(|error| error.message)
+            .unwrap_or_else(|_| REQUEST_FAILED.to_string()))
+    }
+}
+
+/// Logs in, or creates an account, with the username and password typed.
+fn send_login_request(app: &mut App, register: bool) {
+    let path = if register { "/accounts" } else { "/login" };
+    let json = &json!({"username": app.username, "password": app.password});
+    let post_request = Request::post(path)
+        .header("Content-Type", "application/json")
+        .body(Json(json))
+        .expect("Failed to build post request.");
+
+    let task = FetchService::fetch(
+        post_request,
+        app.link
+            .callback(|response: Response<Text>| match read_response(response) {
+                Ok(_) => Msg::LoggedIn,
+                Err(message) => Msg::AccountFailed(message),
+            }),
+    );
+
+    if let Ok(t) = task {
+        app.account_task = Some(t)
+    }
+}
+
+fn send_logout_request(app: &mut App) {
+    let post_request = Request::post("/logout")
+        .body(Nothing)
+        .expect("Failed to build post request.");
+
+    let task = FetchService::fetch(
+        post_request,
+        app.link.callback(|_: Response<Text>| Msg::LoggedOut),
+    );
+
+    if let Ok(t) = task {
+        app.account_task = Some(t)
+    }
+}
+
+/// Loads the saved dice, which also tells whether the user is logged in.
+fn send_load_request(app: &mut App) {
+    let get_request = Request::get("/me/dice")
+        .body(Nothing)
+        .expect("Failed to build get request.");
+
+    let task = FetchService::fetch(
+        get_request,
+        app.link.callback(|response: Response<Text>| {
+            let saved = read_response(response)
+                .ok()
+                .and_then(|body| serde_json::from_str(&body).ok());
+
+            Msg::LoadedDice(saved)
+        }),
+    );
+
+    if let Ok(t) = task {
+        app.account_task = Some(t)
+    }
+}
+
+/// Saves the dice over those last loaded or saved.
+fn send_save_request(app: &mut App, version: u64) {
+    let json = &json!({"version": version, "dice": app.state.dice});
+    let put_request = Request::put("/me/dice")
+        .header("Content-Type", "application/json")
+        .body(Json(json))
+        .expect("Failed to build put request.");
+
+    let task = FetchService::fetch(
+        put_request,
+        app.link.callback(|response: Response<Text>| {
+            let saved = read_response(response).and_then(|body| {
+                serde_json::from_str(&body).map_err(|_| REQUEST_FAILED.to_string())
+            });
+
+            match saved {
+                Ok(saved) => Msg::SavedDice(saved),
+                Err(message) => Msg::AccountFailed(message),
+            }
+        }),
+    );
+
+    if let Ok(t) = task {
+        app.account_task = Some(t)
+    }
+}
+
+/// Dice are told apart by name, so copies of taken names get a number.
+fn unique_name(dice: &[DieData], name: &str) -> String {
+    let taken = |name: &str| dice.iter().any(|d| d.name == name);
+
+    if !taken(name) {
+        return name.to_string();
+    }
+
+    (2..)
+        .map(|n| format!("{} ({})", name, n))
+        .find(|name| !taken(name))
+        .unwrap()
 }
 
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
@@ -40,6 +292,25 @@ pub enum Msg {
     UpdateDie(String, DieData),
     NewDie,
     DeleteDie(String),
+    RollAll,
+    /// Each die's name, with its output.
+    Outputs(Vec<(String, String)>),
+    FetchFailed,
+    SharedSet(Option<SetData>),
+    CopySet,
+    CloseSet,
+    InputUsername(String),
+    InputPassword(String),
+    /// Logs in, or creates an account when set.
+    LogIn(bool),
+    LoggedIn,
+    LogOut,
+    LoggedOut,
+    /// The saved dice, or nothing when not logged in.
+    LoadedDice(Option<SavedDiceData>),
+    SaveDice,
+    SavedDice(SavedData),
+    AccountFailed(String),
 }
 
 impl Component for App {
@@ -59,11 +330,31 @@ impl Component for App {
             }
         };
 
-        App {
+        let shared_id = yew::utils::window()
+            .location()
+            .pathname()
+            .ok()
+            .and_then(|path| path.strip_prefix("/s/").map(str::to_string));
+
+        let mut app = App {
             link,
             storage,
             state,
+            shared: shared_id.as_ref().map(|_| Shared::Loading),
+            fetch_task: None,
+            account: Account::LoggedOut,
+            username: String::new(),
+            password: String::new(),
+            notice: None,
+            account_task: None,
+        };
+
+        if let Some(id) = shared_id {
+            send_set_request(&mut app, &id);
         }
+        send_load_request(&mut app);
+
+        app
     }
 
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
@@ -81,6 +372,113 @@ impl Component for App {
             Msg::DeleteDie(s) => {
                 self.state.dice.retain(|d| d.name != s);
             }
+            Msg::RollAll => {
+                send_batch_request(self);
+                return false;
+            }
+            Msg::Outputs(outputs) => {
+                self.fetch_task = None;
+
+                // Dice may have been added or removed while the batch was
+                // rolling, so results are matched to dice by name.
+                for (name, output) in outputs {
+                    if let Some(die) = self.state.dice.iter_mut().find(|d| d.name == name) {
+                        die.output = output;
+                    }
+                }
+            }
+            Msg::FetchFailed => {
+                self.fetch_task = None;
+                return false;
+            }
+            Msg::SharedSet(set) => {
+                self.fetch_task = None;
+                self.shared = Some(match set {
+                    Some(set) => Shared::Loaded(set),
+                    None => Shared::Missing,
+                });
+                return true;
+            }
+            Msg::CopySet => {
+                if let Some(Shared::Loaded(set)) = self.shared.take() {
+                    for die in set.dice {
+                        let name = unique_name(&self.state.dice, &die.name);
+                        self.state.dice.push(DieData::new(&name, &die.roll, ""));
+                    }
+                }
+            }
+            Msg::CloseSet => {
+                self.shared = None;
+                return true;
+            }
+            Msg::InputUsername(username) => {
+                self.username = username;
+                return false;
+            }
+            Msg::InputPassword(password) => {
+                self.password = password;
+                return false;
+            }
+            Msg::LogIn(register) => {
+                send_login_request(self, register);
+                return false;
+            }
+            Msg::LoggedIn => {
+                self.account_task = None;
+                self.password.clear();
+                self.notice = None;
+                send_load_request(self);
+            }
+            Msg::LogOut => {
+                send_logout_request(self);
+                return false;
+            }
+            Msg::LoggedOut => {
+                self.account_task = None;
+                self.account = Account::LoggedOut;
+                self.notice = None;
+            }
+            Msg::LoadedDice(saved) => {
+                self.account_task = None;
+
+                match saved {
+                    // Nothing was saved yet, so the dice in the browser
+                    // become the account's.
+                    Some(saved) if saved.version == 0 => send_save_request(self, 0),
+                    Some(saved) => {
+                        self.account = Account::LoggedIn {
+                            version: saved.version,
+                        };
+                        self.state.dice = saved.dice;
+                    }
+                    None => self.account = Account::LoggedOut,
+                }
+            }
+            Msg::SaveDice => {
+                if let Account::LoggedIn { version } = self.account {
+                    send_save_request(self, version);
+                }
+                return false;
+            }
+            Msg::SavedDice(saved) => {
+                self.account_task = None;
+                self.account = Account::LoggedIn {
+                    version: saved.version,
+                };
+                self.notice = if saved.conflict {
+                    Some(
+                        "Another device saved these dice since they were loaded here. \
+                         They were replaced with the dice on this page."
+                            .to_string(),
+                    )
+                } else {
+                    Some("Saved.".to_string())
+                };
+            }
+            Msg::AccountFailed(message) => {
+                self.account_task = None;
+                self.notice = Some(message);
+            }
         }
 
         self.storage.store(KEY, Json(&self.state));
@@ -93,6 +491,10 @@ impl Component for App {
     }
 
     fn view(&self) -> Html {
+        if let Some(shared) = &self.shared {
+            return self.view_shared(shared);
+        }
+
         // refactor this concurrent code at some point
         html! {
         <div id="grid">
@@ -104,8 +506,11 @@ impl Component for App {
             modifier, number of dice to drop.]"
             }
             </p>
+            { self.view_account() }
             <button id="new-die-button"
             onclick=self.link.callback(|_| Msg::NewDie)>{ "New die" }</button>
+            <button id="roll-all-button"
+            onclick=self.link.callback(|_| Msg::RollAll)>{ "Roll all" }</button>
             <div id="dice">
             {
                 (0..self.state.dice.len()).map(|index| {
@@ -133,3 +538,83 @@ impl Component for App {
         }
     }
 }
+
+impl App {
+    fn view_account(&self) -> Html {
+        let buttons = match self.account {
+            Account::LoggedOut => html! {
+                <>
+                <input
+                type="text",
+                value=&self.username,
+                placeholder="Username"
+                oninput=self.link.callback(|e: InputData| Msg::InputUsername(e.value)) />
+                <input
+                type="password",
+                value=&self.password,
+                placeholder="Password"
+                oninput=self.link.callback(|e: InputData| Msg::InputPassword(e.value)) />
+                <button onclick=self.link.callback(|_| Msg::LogIn(false))>{ "Log in" }</button>
+                <button onclick=self.link.callback(|_| Msg::LogIn(true))>
+                { "Create account" }</button>
+                </>
+            },
+            Account::LoggedIn { .. } => html! {
+                <>
+                <button id="save-dice-button"
+                onclick=self.link.callback(|_| Msg::SaveDice)>{ "Save dice" }</button>
+                <button onclick=self.link.callback(|_| Msg::LogOut)>{ "Log out" }</button>
+                </>
+            },
+        };
+
+        html! {
+        <div id="account">
+            { buttons }
+            {
+                match &self.notice {
+                    Some(notice) => html! { <p id="account-notice">{ notice }</p> },
+                    None => html! {},
+                }
+            }
+        </div>
+        }
+    }
+
+    fn view_shared(&self, shared: &Shared) -> Html {
+        let close = self.link.callback(|_| Msg::CloseSet);
+
+        let body = match shared {
+            Shared::Loading => html! { <p>{ "Loading the shared set..." }</p> },
+            Shared::Missing => html! {
+                <>
+                <p>{ "This set does not exist, or was deleted." }</p>
+                <button onclick=close>{ "Back to my dice" }</button>
+                </>
+            },
+            Shared::Loaded(set) => html! {
+                <>
+                <h2>{ &set.name }</h2>
+                <p>{ format!("Shared by {}", set.owner) }</p>
+                <ul id="shared-dice">
+                {
+                    set.dice.iter().map(|die| html! {
+                        <li><b>{ &die.name }</b>{ " " }{ &die.roll }</li>
+                    }).collect::<Html>()
+                }
+                </ul>
+                <button id="copy-set-button"
+                onclick=self.link.callback(|_| Msg::CopySet)>{ "Copy to my dice" }</button>
+                <button onclick=close>{ "Back to my dice" }</button>
+                </>
+            },
+        };
+
+        html! {
+        <div id="grid">
+            <h1>{ "DiCast" }</h1>
+            <div id="shared-set">{ body }</div>
+        </div>
+        }
+    }
+}
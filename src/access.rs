@@ -0,0 +1,216 @@
+use futures::future::{BoxFuture, FutureExt};
+use hyper::service::Service;
+use hyper::{Body, Request};
+use rand::Rng;
+use tracing::Instrument;
+use warp::http::header::HeaderValue;
+use warp::reply::Response;
+
+use crate::listen::RemoteAddr;
+use crate::proxy::{ClientIp, Proxies};
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// The header a request's ID is taken from, if a trusted proxy set it,
+/// and returned in.
+const X_REQUEST_ID: &str = "x-request-id";
+/// The longest request ID taken from a proxy.
+const MAX_REQUEST_ID: usize = 64;
+
+/// Gives every request an ID and runs it in a span that carries the ID, so
+/// that everything logged while answering it has it, then logs the request
+/// once it is answered. The client's IP is found here too, and set on the
+/// request for the rate limits.
+#[derive(Clone)]
+pub struct AccessLog<S> {
+    service: S,
+    proxies: Arc<Proxies>,
+}
+
+impl<S> AccessLog<S> {
+    pub fn new(service: S, proxies: Proxies) -> AccessLog<S> {
+        AccessLog {
+            service,
+            proxies: Arc::new(proxies),
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for AccessLog<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Infallible>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let peer = req.extensions().get::<RemoteAddr>().map(|remote| remote.0);
+        // Anyone else could forge IDs, or reuse another request's.
+        let given = if self.proxies.trusts(peer) {
+            req.headers().get(X_REQUEST_ID)
+        } else {
+            None
+        };
+        let id = request_id(given);
+        // The expression is recorded by `metrics::parse` if the request
+        // rolls.
+        let span = tracing::info_span!(
+            target: "dicast::access",
+            "request",
+            request_id = %id,
+            expression = tracing::field::Empty,
+        );
+
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let ip = self.proxies.client_ip(peer, req.headers());
+        req.extensions_mut().insert(ClientIp(ip));
+        let start = Instant::now();
+        let response = span.in_scope(|| self.service.call(req));
+
+        async move {
+            let mut response = match response.await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            };
+            // Generated IDs are hex, and those from proxies were checked.
+            response
+                .headers_mut()
+                .insert(X_REQUEST_ID, HeaderValue::from_str(&id).unwrap());
+
+            tracing::info!(
+                target: "dicast::access",
+                method = %method,
+                path = %path,
+                status = response.status().as_u16(),
+                latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+                ip = ip.map(tracing::field::display),
+                "Answered a request."
+            );
+
+            Ok(response)
+        }
+        .instrument(span)
+        .boxed()
+    }
+}
+
+/// The ID a trusted proxy gave a request, if it is short and plain enough
+/// to log as it is, or else a new one.
+fn request_id(header: Option<&HeaderValue>) -> String {
+    header
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| hex::encode(rand::thread_rng().gen::<[u8; 16]>()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use warp::Filter;
+
+    use crate::logging::tests::json_logger;
+    use crate::proxy::TrustedProxy;
+
+    async fn call(
+        service: &mut impl Service<Request<Body>, Response = Response>,
+        headers: &[(&str, &str)],
+    ) -> Response {
+        let mut req = Request::builder().uri("/roll/3d6?x=1");
+        for &(name, value) in headers {
+            req = req.header(name, value);
+        }
+
+        match service.call(req.body(Body::empty()).unwrap()).await {
+            Ok(response) => response,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_get_ids_and_are_logged() {
+        let (logger, buffer) = json_logger();
+        let _default = tracing::subscriber::set_default(logger);
+
+        let roll = warp::path!("roll" / String).map(|roll: String| {
+            crate::metrics::parse(&roll).unwrap();
+            "11"
+        });
+        let proxies = Proxies::new(vec![TrustedProxy::Unix]);
+        let mut service = AccessLog::new(warp::service(roll), proxies);
+
+        let response = call(&mut service, &[]).await;
+        let id = response.headers()[X_REQUEST_ID]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(32, id.len());
+
+        let response = call(&mut service, &[(X_REQUEST_ID, "nginx-0123")]).await;
+        assert_eq!("nginx-0123", response.headers()[X_REQUEST_ID]);
+
+        let response = call(&mut service, &[(X_REQUEST_ID, "a b")]).await;
+        assert_ne!("a b", response.headers()[X_REQUEST_ID]);
+
+        let lines = buffer.lines();
+        let access = &lines[0];
+        assert_eq!("dicast::access", access["target"]);
+        assert_eq!(id, access["span"]["request_id"]);
+        assert_eq!("3d6", access["span"]["expression"]);
+        assert_eq!("GET", access["method"]);
+        assert_eq!("/roll/3d6", access["path"]);
+        assert_eq!(200, access["status"]);
+        assert!(access["latency_ms"].is_f64());
+        assert_eq!(3, lines.len());
+    }
+
+    #[tokio::test]
+    async fn request_ids_only_come_from_trusted_proxies() {
+        let reply = warp::any().map(warp::reply);
+        let mut service = AccessLog::new(warp::service(reply), Proxies::default());
+
+        let response = call(&mut service, &[(X_REQUEST_ID, "nginx-0123")]).await;
+        assert_ne!("nginx-0123", response.headers()[X_REQUEST_ID]);
+    }
+
+    #[tokio::test]
+    async fn client_ip_comes_from_trusted_proxies() {
+        let (logger, buffer) = json_logger();
+        let _default = tracing::subscriber::set_default(logger);
+
+        let client = crate::proxy::client_ip().map(|ip: Option<std::net::IpAddr>| {
+            ip.map(|ip| ip.to_string()).unwrap_or_default()
+        });
+        let forwarded = [("x-forwarded-for", "203.0.113.7")];
+
+        let mut service = AccessLog::new(warp::service(client.clone()), Proxies::default());
+        let response = call(&mut service, &forwarded).await;
+        assert_eq!(b"", &hyper::body::to_bytes(response).await.unwrap()[..]);
+
+        let proxies = Proxies::new(vec![TrustedProxy::Unix]);
+        let mut service = AccessLog::new(warp::service(client), proxies);
+        let response = call(&mut service, &forwarded).await;
+        assert_eq!(b"203.0.113.7", &hyper::body::to_bytes(response).await.unwrap()[..]);
+
+        let lines = buffer.lines();
+        assert!(lines[0]["ip"].is_null());
+        assert_eq!("203.0.113.7", lines[1]["ip"]);
+    }
+}
@@ -0,0 +1,647 @@
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::error::{blocking, ApiError, ForWarp};
+use crate::ratelimit::{self, Rate, RateLimiter, SystemClock};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const ACCOUNTS_FILE: &str = "accounts.json";
+pub const SESSION_COOKIE: &str = "dicast_session";
+// 30 days
+const SESSION_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+const MAX_SESSIONS: usize = 100_000;
+/// Every save rewrites the accounts file, so the accounts are capped to
+/// keep it small.
+const MAX_ACCOUNTS: usize = 10_000;
+/// How fast each client IP may create accounts.
+const REGISTER_RATE: Rate = Rate {
+    per_minute: 1,
+    burst: 5,
+};
+/// How fast each client IP may try to log in.
+const LOGIN_RATE: Rate = Rate {
+    per_minute: 10,
+    burst: 20,
+};
+/// How fast anyone may try to log in to one account, so that guessing its
+/// password from many IPs is slow too.
+const LOGIN_USER_RATE: Rate = Rate {
+    per_minute: 5,
+    burst: 10,
+};
+const MAX_USERNAME_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_PASSWORD_LEN: usize = 256;
+
+/// A saved die, as the frontend keeps it.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DieData {
+    pub name: String,
+    pub roll: String,
+    pub output: String,
+}
+
+/// A user's saved dice. The version goes up by one with every save.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct SavedDice {
+    pub version: u64,
+    pub dice: Vec<DieData>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Account {
+    /// An encoded argon2id hash, salt and parameters included.
+    password_hash: String,
+    #[serde(default)]
+    dice: SavedDice,
+}
+
+#[derive(Debug)]
+struct Session {
+    username: String,
+    expires: Instant,
+}
+
+/// User accounts, kept in a JSON file in the data directory, and their
+/// sessions, kept in memory so that restarting the server logs everyone
+/// out.
+#[derive(Debug)]
+pub struct Accounts {
+    path: PathBuf,
+    users: Mutex<BTreeMap<String, Account>>,
+    sessions: Mutex<HashMap<String, Session>>,
+    max_accounts: usize,
+}
+
+impl Accounts {
+    /// Loads the accounts in a data directory, creating it if need be.
+    pub fn open(dir: &Path) -> Result<Accounts, AccountsError> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(ACCOUNTS_FILE);
+        let users = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Accounts {
+            path,
+            users: Mutex::new(users),
+            sessions: Mutex::new(HashMap::new()),
+            max_accounts: MAX_ACCOUNTS,
+        })
+    }
+
+    /// Creates an account, returning a session for it.
+    pub fn register(
+        &self,
+        username: &str,
+        password: &str,
+        now: Instant,
+    ) -> Result<String, ApiError> {
+        let username = check_username(username)?;
+        check_password(password)?;
+
+        let password_hash = hash_password(password)?;
+
+        {
+            let mut users = self.users.lock().unwrap();
+            if users.contains_key(&username) {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "username_taken",
+                    "That username is taken.",
+                ));
+            }
+            if users.len() >= self.max_accounts {
+                return Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "too_many_accounts",
+                    "No more accounts can be created.",
+                ));
+            }
+
+            users.insert(
+                username.clone(),
+                Account {
+                    password_hash,
+                    dice: SavedDice::default(),
+                },
+            );
+            self.save(&users)?;
+        }
+
+        self.start_session(username, now)
+    }
+
+    /// Checks a user's password, returning a new session.
+    pub fn login(&self, username: &str, password: &str, now: Instant) -> Result<String, ApiError> {
+        let username = username.trim().to_lowercase();
+        let password_hash = self
+            .users
+            .lock()
+            .unwrap()
+            .get(&username)
+            .map(|account| account.password_hash.clone());
+
+        let valid = match password_hash {
+            Some(password_hash) => argon2::verify_encoded(&password_hash, password.as_bytes())
+                .map_err(AccountsError::from)?,
+            None => {
+                // Hash anyway, so that unknown usernames take as long to
+                // turn away as wrong passwords.
+                hash_password(password)?;
+                false
+            }
+        };
+
+        if !valid {
+            return Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_login",
+                "The username or password is wrong.",
+            ));
+        }
+
+        self.start_session(username, now)
+    }
+
+    pub fn logout(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+
+    /// Finds the user a session cookie belongs to.
+    pub fn user(&self, token: Option<&str>, now: Instant) -> Result<String, ApiError> {
+        let sessions = self.sessions.lock().unwrap();
+
+        token
+            .and_then(|token| sessions.get(token))
+            .filter(|session| session.expires > now)
+            .map(|session| session.username.clone())
+            .ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "not_logged_in",
+                    "Log in to use your saved dice.",
+                )
+                .with_hint("Log in with `POST /login`.")
+            })
+    }
+
+    pub fn dice(&self, username: &str) -> Result<SavedDice, ApiError> {
+        self.users
+            .lock()
+            .unwrap()
+            .get(username)
+            .map(|account| account.dice.clone())
+            .ok_or_else(unknown_user)
+    }
+
+    /// Replaces a user's dice, last writer winning. `version` is the one
+    /// the client last saw; if it isn't the latest, another device saved
+    /// in between, which is reported as a conflict.
+    pub fn save_dice(
+        &self,
+        username: &str,
+        version: u64,
+        dice: Vec<DieData>,
+    ) -> Result<Saved, ApiError> {
+        let mut users = self.users.lock().unwrap();
+        let account = users.get_mut(username).ok_or_else(unknown_user)?;
+
+        let conflict = account.dice.version != version;
+        account.dice = SavedDice {
+            version: account.dice.version + 1,
+            dice,
+        };
+        let version = account.dice.version;
+        self.save(&users)?;
+
+        Ok(Saved { version, conflict })
+    }
+
+    fn start_session(&self, username: String, now: Instant) -> Result<String, ApiError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires > now);
+
+        if sessions.len() >= MAX_SESSIONS {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too_many_sessions",
+                "Too many users are logged in.",
+            ));
+        }
+
+        let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        sessions.insert(
+            token.clone(),
+            Session {
+                username,
+                expires: now + SESSION_TTL,
+            },
+        );
+
+        Ok(token)
+    }
+
+    /// Writes every account out, through a temporary file so that a crash
+    /// can't leave the file half written.
+    fn save(&self, users: &BTreeMap<String, Account>) -> Result<(), AccountsError> {
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(users)?)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+fn check_username(username: &str) -> Result<String, ApiError> {
+    let username = username.trim().to_lowercase();
+
+    if username.is_empty()
+        || username.len() > MAX_USERNAME_LEN
+        || !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_username",
+            format!(
+                "Usernames are 1 to {} letters, digits, dots, dashes or underscores.",
+                MAX_USERNAME_LEN
+            ),
+        ));
+    }
+
+    Ok(username)
+}
+
+fn check_password(password: &str) -> Result<(), ApiError> {
+    let len = password.chars().count();
+
+    if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&len) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_password",
+            format!(
+                "Passwords are {} to {} characters.",
+                MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+fn hash_password(password: &str) -> Result<String, AccountsError> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        ..argon2::Config::default()
+    };
+    let salt = rand::thread_rng().gen::<[u8; 16]>();
+
+    Ok(argon2::hash_encoded(password.as_bytes(), &salt, &config)?)
+}
+
+fn unknown_user() -> ApiError {
+    ApiError::new(
+        StatusCode::UNAUTHORIZED,
+        "not_logged_in",
+        "The account no longer exists.",
+    )
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// The result of saving dice.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Saved {
+    pub version: u64,
+    /// Whether another save came in since the version the client sent.
+    pub conflict: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SaveRequest {
+    version: u64,
+    dice: Vec<DieData>,
+}
+
+/// `POST /accounts`, `POST /login`, `POST /logout` and `GET/PUT /me/dice`.
+/// When served over HTTPS, `secure` keeps the session cookie to it.
+pub fn routes(accounts: Arc<Accounts>, body_limit: u64, secure: bool) -> BoxedFilter<(Response,)> {
+    let with_accounts = warp::any().map(move || accounts.clone());
+    let session = warp::cookie::optional(SESSION_COOKIE);
+
+    let register_limit = ratelimit::by_ip(Arc::new(RateLimiter::new(
+        REGISTER_RATE,
+        Arc::new(SystemClock),
+    )));
+
+    let login_limit = ratelimit::by_ip(Arc::new(RateLimiter::new(
+        LOGIN_RATE,
+        Arc::new(SystemClock),
+    )));
+    let login_user_limit = Arc::new(RateLimiter::new(LOGIN_USER_RATE, Arc::new(SystemClock)));
+
+    // Hashing a password takes a while, and saving rewrites the accounts
+    // file, so both are done on a blocking thread.
+    let register = warp::filters::method::post()
+        .and(warp::path("accounts"))
+        .and(warp::path::end())
+        .and(register_limit)
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::json())
+        .and(with_accounts.clone())
+        .and_then(move |req: Credentials, accounts: Arc<Accounts>| async move {
+            blocking(move || accounts.register(&req.username, &req.password, Instant::now()))
+                .await
+                .map(|token| {
+                    let reply = warp::reply::with_status(warp::reply(), StatusCode::CREATED);
+                    with_session(reply, &token, secure)
+                })
+                .for_warp()
+        });
+
+    let login = warp::filters::method::post()
+        .and(warp::path("login"))
+        .and(warp::path::end())
+        .and(login_limit)
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::json())
+        .and(with_accounts.clone())
+        .and_then(move |req: Credentials, accounts: Arc<Accounts>| {
+            let limited = login_user_limit.check(req.username.trim().to_lowercase());
+
+            async move {
+                limited.map_err(warp::reject::custom)?;
+                blocking(move || accounts.login(&req.username, &req.password, Instant::now()))
+                    .await
+                    .map(|token| with_session(warp::reply(), &token, secure))
+                    .for_warp()
+            }
+        });
+
+    let logout = warp::filters::method::post()
+        .and(warp::path("logout"))
+        .and(warp::path::end())
+        .and(session)
+        .and(with_accounts.clone())
+        .map(move |token: Option<String>, accounts: Arc<Accounts>| {
+            if let Some(token) = token {
+                accounts.logout(&token);
+            }
+
+            let cookie = session_cookie("", 0, secure);
+            warp::reply::with_header(warp::reply(), "set-cookie", cookie).into_response()
+        });
+
+    let get_dice = warp::filters::method::get()
+        .and(warp::path!("me" / "dice"))
+        .and(session)
+        .and(with_accounts.clone())
+        .and_then(
+            |token: Option<String>, accounts: Arc<Accounts>| async move {
+                accounts
+                    .user(token.as_deref(), Instant::now())
+                    .and_then(|user| accounts.dice(&user))
+                    .map(|dice| warp::reply::json(&dice).into_response())
+                    .for_warp()
+            },
+        );
+
+    let put_dice = warp::filters::method::put()
+        .and(warp::path!("me" / "dice"))
+        .and(session)
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::json())
+        .and(with_accounts)
+        .and_then(
+            |token: Option<String>, req: SaveRequest, accounts: Arc<Accounts>| async move {
+                let user = accounts
+                    .user(token.as_deref(), Instant::now())
+                    .map_err(warp::reject::custom)?;
+                blocking(move || accounts.save_dice(&user, req.version, req.dice))
+                    .await
+                    .map(|saved| warp::reply::json(&saved).into_response())
+                    .for_warp()
+            },
+        );
+
+    register
+        .or(login)
+        .unify()
+        .or(logout)
+        .unify()
+        .or(get_dice)
+        .unify()
+        .or(put_dice)
+        .unify()
+        .boxed()
+}
+
+fn with_session(reply: impl Reply, token: &str, secure: bool) -> Response {
+    let cookie = session_cookie(token, SESSION_TTL.as_secs(), secure);
+
+    warp::reply::with_header(reply, "set-cookie", cookie).into_response()
+}
+
+/// A `Set-Cookie` value for the session cookie. A `secure` cookie is only
+/// sent back over HTTPS.
+fn session_cookie(token: &str, max_age: u64, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}",
+        SESSION_COOKIE,
+        token,
+        max_age,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+#[derive(Debug, Error)]
+pub enum AccountsError {
+    #[error("Could not access accounts: {0}")]
+    Io(#[from] io::Error),
+    #[error("Could not encode accounts: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Could not hash password: {0}")]
+    Hash(#[from] argon2::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn die(name: &str) -> DieData {
+        DieData {
+            name: name.to_string(),
+            roll: "1d20".to_string(),
+            output: String::new(),
+        }
+    }
+
+    #[test]
+    fn accounts_register_and_login() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = Accounts::open(dir.path()).unwrap();
+        let now = Instant::now();
+
+        let token = accounts.register("Ann", "correct horse", now).unwrap();
+        assert_eq!("ann", accounts.user(Some(&token), now).unwrap());
+
+        let error = accounts.register("ann", "other password", now).unwrap_err();
+        assert_eq!("username_taken", error.body.code);
+        let error = accounts.register("a b", "correct horse", now).unwrap_err();
+        assert_eq!("invalid_username", error.body.code);
+        let error = accounts.register("bo", "short", now).unwrap_err();
+        assert_eq!("invalid_password", error.body.code);
+
+        drop(accounts);
+        let accounts = Accounts::open(dir.path()).unwrap();
+        assert!(accounts.user(Some(&token), now).is_err());
+
+        let error = accounts.login("ann", "wrong horse", now).unwrap_err();
+        assert_eq!("invalid_login", error.body.code);
+        let error = accounts.login("bo", "correct horse", now).unwrap_err();
+        assert_eq!("invalid_login", error.body.code);
+
+        let token = accounts.login("Ann", "correct horse", now).unwrap();
+        assert!(accounts.user(Some(&token), now).is_ok());
+        assert!(accounts.user(Some(&token), now + SESSION_TTL).is_err());
+
+        accounts.logout(&token);
+        assert!(accounts.user(Some(&token), now).is_err());
+    }
+
+    #[test]
+    fn accounts_are_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut accounts = Accounts::open(dir.path()).unwrap();
+        accounts.max_accounts = 1;
+        let now = Instant::now();
+
+        accounts.register("ann", "correct horse", now).unwrap();
+        let error = accounts.register("bo", "correct horse", now).unwrap_err();
+        assert_eq!("too_many_accounts", error.body.code);
+    }
+
+    #[test]
+    fn accounts_save_dice() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = Accounts::open(dir.path()).unwrap();
+        accounts
+            .register("ann", "correct horse", Instant::now())
+            .unwrap();
+
+        assert_eq!(SavedDice::default(), accounts.dice("ann").unwrap());
+
+        let saved = accounts.save_dice("ann", 0, vec![die("a")]).unwrap();
+        assert_eq!((1, false), (saved.version, saved.conflict));
+
+        // A second device that last saw version 0 still wins, but hears
+        // of the conflict.
+        let saved = accounts.save_dice("ann", 0, vec![die("b")]).unwrap();
+        assert_eq!((2, true), (saved.version, saved.conflict));
+
+        drop(accounts);
+        let accounts = Accounts::open(dir.path()).unwrap();
+        let dice = accounts.dice("ann").unwrap();
+        assert_eq!(2, dice.version);
+        assert_eq!(vec![die("b")], dice.dice);
+    }
+
+    #[test]
+    fn session_cookie_is_secure_over_https() {
+        assert_eq!(
+            "dicast_session=abc; Path=/; HttpOnly; SameSite=Lax; Max-Age=60; Secure",
+            session_cookie("abc", 60, true)
+        );
+        assert!(!session_cookie("", 0, false).contains("Secure"));
+    }
+
+    #[tokio::test]
+    async fn me_dice_needs_a_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = Arc::new(Accounts::open(dir.path()).unwrap());
+        let routes = routes(accounts, 1024, false).recover(crate::error::handle_rejection);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/me/dice")
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/accounts")
+            .json(&serde_json::json!({ "username": "ann", "password": "correct horse" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::CREATED, response.status());
+
+        let cookie = response.headers()["set-cookie"].to_str().unwrap();
+        let session = cookie.split(';').next().unwrap().to_string();
+        assert!(session.starts_with("dicast_session="));
+        assert!(!cookie.contains("Secure"));
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/me/dice")
+            .header("cookie", &session)
+            .json(&serde_json::json!({ "version": 0, "dice": [die("a")] }))
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, response.status());
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/me/dice")
+            .header("cookie", &session)
+            .reply(&routes)
+            .await;
+        let dice = serde_json::from_slice::<SavedDice>(response.body()).unwrap();
+        assert_eq!(1, dice.version);
+    }
+
+    #[tokio::test]
+    async fn login_is_limited_per_username() {
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = Arc::new(Accounts::open(dir.path()).unwrap());
+        let routes = routes(accounts, 1024, false).recover(crate::error::handle_rejection);
+
+        let login = |username: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/login")
+                .json(&serde_json::json!({ "username": username, "password": "wrong horse" }))
+        };
+
+        for _ in 0..LOGIN_USER_RATE.burst {
+            let response = login("ann").reply(&routes).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        }
+        let response = login(" Ann").reply(&routes).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+
+        let response = login("bo").reply(&routes).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+}
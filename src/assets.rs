@@ -0,0 +1,576 @@
+use sha2::{Digest, Sha256};
+use warp::filters::BoxedFilter;
+use warp::http::header::{CACHE_CONTROL, CONTENT_ENCODING, ETAG, VARY};
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
+use warp::reply::Response;
+use warp::Filter;
+
+use crate::compress;
+use crate::config::Config;
+use crate::metrics;
+use crate::mime::{Mime, MimeAware};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Compressing at startup happens on every launch, so it trades a little
+/// size for speed compared to the build script.
+const BROTLI_RUNTIME: u32 = 9;
+
+const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const CACHE_REVALIDATE: &str = "no-cache";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// Picks the compressed encoding out of those available that an
+    /// `Accept-Encoding` header weighs highest, preferring brotli on ties.
+    pub fn negotiate(accept_encoding: Option<&str>, available: &[Encoding]) -> Encoding {
+        let accept_encoding = match accept_encoding {
+            Some(accept_encoding) => accept_encoding,
+            None => return Encoding::Identity,
+        };
+
+        let weights = accept_encoding
+            .split(',')
+            .filter_map(|part| {
+                let mut params = part.split(';').map(str::trim);
+                let token = params.next()?.to_ascii_lowercase();
+                let q = params
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((token, q))
+            })
+            .collect::<Vec<_>>();
+
+        let weight = |encoding: Encoding| {
+            weights
+                .iter()
+                .find(|(token, _)| token == encoding.token())
+                .or_else(|| weights.iter().find(|(token, _)| token == "*"))
+                .map(|&(_, q)| q)
+                .unwrap_or(0.0)
+        };
+
+        [Encoding::Brotli, Encoding::Gzip]
+            .iter()
+            .copied()
+            .filter(|encoding| available.contains(encoding))
+            .map(|encoding| (encoding, weight(encoding)))
+            .filter(|&(_, q)| q > 0.0)
+            .fold(None, |best: Option<(Encoding, f32)>, (encoding, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((encoding, q)),
+            })
+            .map(|(encoding, _)| encoding)
+            .unwrap_or(Encoding::Identity)
+    }
+}
+
+/// A frontend file held in memory, along with its compressed forms.
+#[derive(Debug, Clone)]
+pub struct Asset {
+    pub mime: Mime,
+    /// The SHA-256 of the uncompressed contents, in hex. It stays the same
+    /// across builds and platforms, so ETags and fingerprinted names do too.
+    pub hash: String,
+    /// Whether the asset is served under a name that changes with its
+    /// contents, so clients may cache it forever.
+    pub immutable: bool,
+    identity: Bytes,
+    gzip: Option<Bytes>,
+    brotli: Option<Bytes>,
+}
+
+impl Asset {
+    pub fn new(mime: Mime, bytes: impl Into<Bytes>) -> Self {
+        let identity = bytes.into();
+        let hash = hex::encode(Sha256::digest(&identity));
+
+        Asset {
+            mime,
+            hash,
+            immutable: false,
+            identity,
+            gzip: None,
+            brotli: None,
+        }
+    }
+
+    /// Attaches forms compressed ahead of time.
+    #[cfg(feature = "embed")]
+    pub fn with_compressed(mut self, gzip: impl Into<Bytes>, brotli: impl Into<Bytes>) -> Self {
+        self.gzip = Some(gzip.into());
+        self.brotli = Some(brotli.into());
+        self
+    }
+
+    /// Compresses the asset if it has not been already, keeping only the
+    /// forms that come out smaller.
+    pub fn compress(mut self) -> Self {
+        if !self.mime.is_compressible() {
+            return self;
+        }
+
+        let smaller = |compressed: Vec<u8>, original: &Bytes| {
+            if compressed.len() < original.len() {
+                Some(Bytes::from(compressed))
+            } else {
+                None
+            }
+        };
+
+        if self.gzip.is_none() {
+            self.gzip = smaller(compress::gzip(&self.identity), &self.identity);
+        }
+        if self.brotli.is_none() {
+            self.brotli = smaller(
+                compress::brotli(&self.identity, BROTLI_RUNTIME),
+                &self.identity,
+            );
+        }
+
+        self
+    }
+
+    fn encodings(&self) -> Vec<Encoding> {
+        let mut encodings = vec![];
+        if self.gzip.is_some() {
+            encodings.push(Encoding::Gzip);
+        }
+        if self.brotli.is_some() {
+            encodings.push(Encoding::Brotli);
+        }
+        encodings
+    }
+
+    fn body(&self, encoding: Encoding) -> Bytes {
+        let body = match encoding {
+            Encoding::Identity => None,
+            Encoding::Gzip => self.gzip.as_ref(),
+            Encoding::Brotli => self.brotli.as_ref(),
+        };
+
+        body.unwrap_or(&self.identity).clone()
+    }
+
+    /// The strong ETag of the asset in an encoding. Each encoding is a
+    /// different representation, so each gets its own tag.
+    pub fn etag(&self, encoding: Encoding) -> String {
+        match encoding {
+            Encoding::Identity => format!("\"{}\"", self.hash),
+            encoding => format!("\"{}-{}\"", self.hash, encoding.token()),
+        }
+    }
+
+    /// Replies with the asset in the best encoding the client accepts, or
+    /// with 304 if the client already has it.
+    pub fn reply(&self, accept_encoding: Option<&str>, if_none_match: Option<&str>) -> Response {
+        let encodings = self.encodings();
+        let encoding = Encoding::negotiate(accept_encoding, &encodings);
+        let etag = self.etag(encoding);
+        let cache_control = if self.immutable {
+            CACHE_IMMUTABLE
+        } else {
+            CACHE_REVALIDATE
+        };
+
+        let mut builder = http::Response::builder()
+            .header(ETAG, &etag)
+            .header(CACHE_CONTROL, cache_control);
+
+        if !encodings.is_empty() {
+            builder = builder.header(VARY, "accept-encoding");
+        }
+
+        let response = if matches(if_none_match, &etag) {
+            builder.status(304).body(Body::empty())
+        } else {
+            if encoding != Encoding::Identity {
+                builder = builder.header(CONTENT_ENCODING, encoding.token());
+            }
+
+            builder
+                .content_type(self.mime.clone())
+                .body(Body::from(self.body(encoding)))
+        };
+
+        response.expect("asset response headers are valid")
+    }
+}
+
+/// Whether an `If-None-Match` header matches an ETag.
+fn matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    match if_none_match {
+        Some(tags) => tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag == etag || tag.strip_prefix("W/") == Some(etag)),
+        None => false,
+    }
+}
+
+/// The frontend, keyed by request path.
+#[derive(Debug, Clone, Default)]
+pub struct Assets {
+    assets: HashMap<String, Asset>,
+}
+
+impl Assets {
+    pub fn insert(&mut self, name: &str, asset: Asset) {
+        self.assets.insert(name.to_string(), asset);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Asset> {
+        self.assets.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.assets.len()
+    }
+
+    /// Serves every asset but HTML under a second name containing its
+    /// hash, such as `main.0123abcd.js`, which may be cached forever.
+    /// HTML is rewritten to refer to assets by those names.
+    pub fn fingerprint(&mut self) {
+        let aliases = self
+            .assets
+            .iter()
+            .filter(|(_, asset)| asset.mime != Mime::Html)
+            .map(|(name, asset)| (name.clone(), fingerprinted(name, &asset.hash)))
+            .collect::<Vec<_>>();
+
+        for (name, alias) in &aliases {
+            let mut asset = self.assets[name].clone();
+            asset.immutable = true;
+            self.assets.insert(alias.clone(), asset);
+        }
+
+        for asset in self.assets.values_mut() {
+            if asset.mime != Mime::Html {
+                continue;
+            }
+
+            let mut html = String::from_utf8_lossy(&asset.identity).into_owned();
+            for (name, alias) in &aliases {
+                html = html.replace(&format!("\"/{}\"", name), &format!("\"/{}\"", alias));
+            }
+
+            *asset = Asset::new(Mime::Html, html);
+        }
+    }
+
+    /// Compresses every asset that was not compressed ahead of time.
+    pub fn compress(&mut self) {
+        for asset in self.assets.values_mut() {
+            *asset = asset.clone().compress();
+        }
+    }
+
+    /// The frontend compiled into the binary, with all but the HTML
+    /// compressed by the build script.
+    #[cfg(feature = "embed")]
+    pub fn embedded() -> Assets {
+        macro_rules! precompressed {
+            ($mime:expr, $path:literal, $name:literal) => {
+                Asset::new($mime, &include_bytes!($path)[..]).with_compressed(
+                    &include_bytes!(concat!(env!("OUT_DIR"), "/", $name, ".gz"))[..],
+                    &include_bytes!(concat!(env!("OUT_DIR"), "/", $name, ".br"))[..],
+                )
+            };
+        }
+
+        let mut assets = Assets::default();
+
+        assets.insert(
+            "index.html",
+            Asset::new(Mime::Html, &include_bytes!("../static/index.html")[..]),
+        );
+        assets.insert(
+            "overlay.html",
+            Asset::new(Mime::Html, &include_bytes!("../static/overlay.html")[..]),
+        );
+        assets.insert(
+            "style.css",
+            precompressed!(Mime::Css, "../static/style.css", "style.css"),
+        );
+        assets.insert(
+            "main.js",
+            precompressed!(Mime::Js, "../frontend/static/main.js", "main.js"),
+        );
+        assets.insert(
+            "main_bg.wasm",
+            precompressed!(
+                Mime::Wasm,
+                "../frontend/static/main_bg.wasm",
+                "main_bg.wasm"
+            ),
+        );
+
+        assets
+    }
+
+    /// Reads the frontend from the paths in the config. Files that are
+    /// missing are left out, so they are answered with 404.
+    #[cfg(not(feature = "embed"))]
+    pub fn load(config: &Config) -> Assets {
+        use std::fs;
+        use std::path::Path;
+
+        fn walk(assets: &mut Assets, root: &Path, dir: &Path) {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::warn!("Could not read {}: {}", dir.display(), e);
+                    return;
+                }
+            };
+
+            for entry in entries.filter_map(Result::ok) {
+                let path = entry.path();
+
+                if path.is_dir() {
+                    walk(assets, root, &path);
+                } else if let Ok(relative) = path.strip_prefix(root) {
+                    let name = relative
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    read(assets, &name, &path);
+                }
+            }
+        }
+
+        fn read(assets: &mut Assets, name: &str, path: &Path) {
+            match fs::read(path) {
+                Ok(bytes) => assets.insert(name, Asset::new(Mime::from_path(name), bytes)),
+                Err(e) => log::warn!("Could not read {}: {}", path.display(), e),
+            }
+        }
+
+        let mut assets = Assets::default();
+        walk(&mut assets, &config.static_dir, &config.static_dir);
+        read(&mut assets, "main.js", &config.app_js);
+        read(&mut assets, "main_bg.wasm", &config.app_wasm);
+
+        assets
+    }
+}
+
+/// Inserts the start of a hash before a name's extension.
+fn fingerprinted(name: &str, hash: &str) -> String {
+    let hash = &hash[..8];
+
+    match name.rfind('.') {
+        Some(dot) if !name[dot..].contains('/') => {
+            format!("{}.{}{}", &name[..dot], hash, &name[dot..])
+        }
+        _ => format!("{}.{}", name, hash),
+    }
+}
+
+/// Whether a path is `s/{id}`, a link to a shared dice set.
+fn is_share_link(path: &str) -> bool {
+    match path.strip_prefix("s/") {
+        Some(id) => !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()),
+        None => false,
+    }
+}
+
+/// Serves the frontend, compressed and with caching headers.
+pub fn routes(config: &Config) -> BoxedFilter<(Response,)> {
+    #[cfg(feature = "embed")]
+    let mut assets = {
+        let _ = config;
+        Assets::embedded()
+    };
+    #[cfg(not(feature = "embed"))]
+    let mut assets = Assets::load(config);
+
+    assets.fingerprint();
+    assets.compress();
+    log::info!("Serving {} assets.", assets.len());
+
+    let assets = Arc::new(assets);
+
+    warp::filters::method::get()
+        .and(warp::path::tail())
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(
+            move |tail: warp::path::Tail,
+                  accept_encoding: Option<String>,
+                  if_none_match: Option<String>| {
+                let assets = assets.clone();
+
+                async move {
+                    let name = match tail.as_str() {
+                        "" => "index.html".to_string(),
+                        // Share links to dice sets are opened by the frontend.
+                        name if is_share_link(name) => "index.html".to_string(),
+                        name if name.ends_with('/') => format!("{}index.html", name),
+                        name => name.to_string(),
+                    };
+
+                    match assets.get(&name) {
+                        Some(asset) => {
+                            metrics::asset_hit(&name);
+                            Ok(asset.reply(accept_encoding.as_deref(), if_none_match.as_deref()))
+                        }
+                        None => Err(warp::reject::not_found()),
+                    }
+                }
+            },
+        )
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_negotiate() {
+        use Encoding::*;
+
+        let both = [Gzip, Brotli];
+
+        assert_eq!(Identity, Encoding::negotiate(None, &both));
+        assert_eq!(Brotli, Encoding::negotiate(Some("gzip, deflate, br"), &both));
+        assert_eq!(Gzip, Encoding::negotiate(Some("gzip, deflate, br"), &[Gzip]));
+        assert_eq!(Gzip, Encoding::negotiate(Some("br;q=0.5, gzip"), &both));
+        assert_eq!(Identity, Encoding::negotiate(Some("br;q=0, gzip;q=0"), &both));
+        assert_eq!(Brotli, Encoding::negotiate(Some("*"), &both));
+        assert_eq!(Identity, Encoding::negotiate(Some("deflate"), &both));
+    }
+
+    #[test]
+    fn share_links() {
+        assert!(is_share_link("s/aB3dE6gH"));
+        assert!(!is_share_link("s/"));
+        assert!(!is_share_link("s/a/b"));
+        assert!(!is_share_link("style.css"));
+    }
+
+    #[test]
+    fn asset_etag_tracks_contents() {
+        let a = Asset::new(Mime::Css, &b"body {}"[..]);
+        let b = Asset::new(Mime::Css, &b"body {}"[..]);
+        let c = Asset::new(Mime::Css, &b"p {}"[..]);
+
+        assert_eq!(a.etag(Encoding::Identity), b.etag(Encoding::Identity));
+        assert_ne!(a.etag(Encoding::Identity), c.etag(Encoding::Identity));
+        assert_ne!(a.etag(Encoding::Identity), a.etag(Encoding::Gzip));
+    }
+
+    #[test]
+    fn asset_reply_compressed() {
+        let asset = Asset::new(Mime::Css, "p { color: red; }\n".repeat(100)).compress();
+
+        let response = asset.reply(Some("gzip, br"), None);
+        assert_eq!(200, response.status());
+        assert_eq!("br", response.headers()["content-encoding"]);
+        assert_eq!("accept-encoding", response.headers()["vary"]);
+
+        let response = asset.reply(None, None);
+        assert!(response.headers().get("content-encoding").is_none());
+    }
+
+    #[test]
+    fn asset_reply_not_modified() {
+        let asset = Asset::new(Mime::Html, &b"<html></html>"[..]);
+
+        let fresh = asset.reply(None, None);
+        assert_eq!(200, fresh.status());
+        assert_eq!("text/html; charset=utf-8", fresh.headers()["content-type"]);
+        assert_eq!(CACHE_REVALIDATE, fresh.headers()["cache-control"]);
+
+        let tags = format!("\"other\", W/{}", asset.etag(Encoding::Identity));
+        assert_eq!(304, asset.reply(None, Some(&tags)).status());
+        assert_eq!(200, asset.reply(None, Some("\"other\"")).status());
+    }
+
+    #[test]
+    fn fingerprinted_page_finds_every_asset() {
+        // How wasm-bindgen's glue finds the wasm when `init` isn't told.
+        let glue = "input = import.meta.url.replace(/\\.js$/, '_bg.wasm');";
+
+        let mut assets = Assets::default();
+        assets.insert(
+            "index.html",
+            Asset::new(Mime::Html, &include_bytes!("../static/index.html")[..]),
+        );
+        assets.insert("style.css", Asset::new(Mime::Css, &b"body {}"[..]));
+        assets.insert("main.js", Asset::new(Mime::Js, glue));
+        assets.insert("main_bg.wasm", Asset::new(Mime::Wasm, &b"\0asm"[..]));
+        assets.fingerprint();
+
+        let html = &assets.get("index.html").unwrap().identity;
+        let html = std::str::from_utf8(html).unwrap();
+        let urls = html
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .filter_map(|quoted| quoted.strip_prefix('/'))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        let script = urls.iter().find(|url| url.ends_with(".js")).unwrap();
+        let wasm = urls
+            .iter()
+            .find(|url| url.ends_with(".wasm"))
+            .cloned()
+            .unwrap_or_else(|| script.replace(".js", "_bg.wasm"));
+
+        for url in urls.iter().chain(std::iter::once(&wasm)) {
+            let asset = assets
+                .get(url)
+                .unwrap_or_else(|| panic!("{} is not served", url));
+            assert!(asset.immutable, "{} is not fingerprinted", url);
+        }
+    }
+
+    #[test]
+    fn assets_fingerprint() {
+        let mut assets = Assets::default();
+        assets.insert(
+            "index.html",
+            Asset::new(Mime::Html, &b"<script src=\"/main.js\"></script>"[..]),
+        );
+        assets.insert("main.js", Asset::new(Mime::Js, &b"init()"[..]));
+        assets.fingerprint();
+
+        let alias = fingerprinted("main.js", &assets.get("main.js").unwrap().hash);
+        let html = &assets.get("index.html").unwrap().identity;
+
+        assert!(assets.get(&alias).unwrap().immutable);
+        assert!(!assets.get("main.js").unwrap().immutable);
+        assert_eq!(
+            format!("<script src=\"/{}\"></script>", alias).as_bytes(),
+            &html[..]
+        );
+    }
+
+    #[test]
+    fn fingerprinted_names() {
+        assert_eq!("main.01234567.js", fingerprinted("main.js", "0123456789abcdef"));
+        assert_eq!("LICENSE.01234567", fingerprinted("LICENSE", "0123456789abcdef"));
+        assert_eq!("a.b/c.01234567", fingerprinted("a.b/c", "0123456789abcdef"));
+    }
+}
@@ -0,0 +1,28 @@
+//! Compression shared by the server and the build script, which
+//! precompresses embedded assets.
+
+use std::io::Write;
+
+/// Brotli's highest quality, slow enough that it is only worth it at
+/// build time.
+#[allow(dead_code)]
+pub const BROTLI_BEST: u32 = 11;
+
+pub fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder
+        .write_all(bytes)
+        .expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+pub fn brotli(bytes: &[u8], quality: u32) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, quality, 22);
+        encoder
+            .write_all(bytes)
+            .expect("writing to a Vec cannot fail");
+    }
+    compressed
+}
@@ -0,0 +1,706 @@
+use serde_derive::Deserialize;
+use structopt::StructOpt;
+use thiserror::Error;
+use warp::http::StatusCode;
+
+use dice::dice::StdDice;
+
+use crate::error::ApiError;
+use crate::listen::Listen;
+use crate::logging::LogFormat;
+use crate::proxy::TrustedProxy;
+use crate::ratelimit::Rate;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+const ADDR: &str = "0.0.0.0:3000";
+const STATIC_DIR_PATH: &str = "./static/";
+const APP_JS: &str = "./frontend/static/main.js";
+const APP_WASM: &str = "./frontend/static/main_bg.wasm";
+const TABLES_DIR_PATH: &str = "./tables/";
+const DATA_DIR_PATH: &str = "./data/";
+// 16kb
+const BODY_LIMIT: u64 = 1024 * 16;
+const LOG_LEVEL: &str = "info";
+// An hour
+const ROOM_IDLE_SECS: u64 = 60 * 60;
+// An hour
+const DECK_IDLE_SECS: u64 = 60 * 60;
+const SIMULATION_THREADS: usize = 4;
+const RATE_LIMIT: Rate = Rate {
+    per_minute: 600,
+    burst: 60,
+};
+const ROOM_RATE_LIMIT: Rate = Rate {
+    per_minute: 120,
+    burst: 20,
+};
+
+/// Command line flags. Each can also be set through an environment
+/// variable, and both take precedence over the config file.
+#[derive(Debug, Default, StructOpt)]
+#[structopt(name = "dicast", about = "A dice rolling web application.")]
+pub struct Opt {
+    /// Path to a TOML config file.
+    #[structopt(short, long, env = "DICAST_CONFIG", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Address to listen on.
+    #[structopt(long, env = "DICAST_ADDR")]
+    pub addr: Option<SocketAddr>,
+    /// Where to listen instead of --addr: an address, unix:PATH for a Unix
+    /// domain socket, or systemd for a socket passed in through LISTEN_FDS.
+    #[structopt(long, env = "DICAST_LISTEN")]
+    pub listen: Option<Listen>,
+    /// Proxy whose X-Forwarded-For and X-Real-IP headers are believed: an
+    /// IP address, or unix for whatever connects over the Unix socket.
+    /// Repeat it, or separate them with commas, to trust several.
+    #[structopt(long = "trusted-proxy", env = "DICAST_TRUSTED_PROXIES", use_delimiter = true)]
+    pub trusted_proxies: Vec<TrustedProxy>,
+    /// Directory of static files, such as index.html.
+    #[structopt(long, env = "DICAST_STATIC_DIR", parse(from_os_str))]
+    pub static_dir: Option<PathBuf>,
+    /// Path to the frontend's main.js.
+    #[structopt(long, env = "DICAST_APP_JS", parse(from_os_str))]
+    pub app_js: Option<PathBuf>,
+    /// Path to the frontend's main_bg.wasm.
+    #[structopt(long, env = "DICAST_APP_WASM", parse(from_os_str))]
+    pub app_wasm: Option<PathBuf>,
+    /// Directory of random tables.
+    #[structopt(long, env = "DICAST_TABLES_DIR", parse(from_os_str))]
+    pub tables_dir: Option<PathBuf>,
+    /// Directory the roll history is kept in.
+    #[structopt(long, env = "DICAST_DATA_DIR", parse(from_os_str))]
+    pub data_dir: Option<PathBuf>,
+    /// Largest request body accepted, in bytes.
+    #[structopt(long, env = "DICAST_BODY_LIMIT")]
+    pub body_limit: Option<u64>,
+    /// Log level, used when RUST_LOG is not set.
+    #[structopt(long, env = "DICAST_LOG_LEVEL")]
+    pub log_level: Option<String>,
+    /// How log lines are written: text, or json for one object per line.
+    #[structopt(long, env = "DICAST_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Seconds a room may sit empty before it is closed.
+    #[structopt(long, env = "DICAST_ROOM_IDLE")]
+    pub room_idle: Option<u64>,
+    /// Seconds a deck session may go unused before it is closed.
+    #[structopt(long, env = "DICAST_DECK_IDLE")]
+    pub deck_idle: Option<u64>,
+    /// Threads dice stats simulations share, at most one per CPU.
+    #[structopt(long, env = "DICAST_SIMULATION_THREADS")]
+    pub simulation_threads: Option<usize>,
+    /// Most dice a single roll may have.
+    #[structopt(long, env = "DICAST_MAX_COUNT")]
+    pub max_count: Option<i64>,
+    /// Most sides a die may have.
+    #[structopt(long, env = "DICAST_MAX_SIDES")]
+    pub max_sides: Option<i64>,
+    /// Most times a roll may be repeated.
+    #[structopt(long, env = "DICAST_MAX_TIMES")]
+    pub max_times: Option<i64>,
+    /// Most trials a simulation may run.
+    #[structopt(long, env = "DICAST_MAX_TRIALS")]
+    pub max_trials: Option<u64>,
+    /// Most rolls a batch may hold.
+    #[structopt(long, env = "DICAST_MAX_BATCH")]
+    pub max_batch: Option<usize>,
+    /// Most dice a request may roll in all, counting every repeat, trial
+    /// and roll of a batch.
+    #[structopt(long, env = "DICAST_MAX_DICE")]
+    pub max_dice: Option<i64>,
+    /// Requests a client IP may make per minute, or 0 for no limit.
+    #[structopt(long, env = "DICAST_RATE_LIMIT")]
+    pub rate_limit: Option<u32>,
+    /// Requests a client IP may make at once.
+    #[structopt(long, env = "DICAST_RATE_BURST")]
+    pub rate_burst: Option<u32>,
+    /// Rolls a room may have per minute, or 0 for no limit.
+    #[structopt(long, env = "DICAST_ROOM_RATE_LIMIT")]
+    pub room_rate_limit: Option<u32>,
+    /// Rolls a room may have at once.
+    #[structopt(long, env = "DICAST_ROOM_RATE_BURST")]
+    pub room_rate_burst: Option<u32>,
+    /// PEM certificate chain to serve HTTPS with. Needs --tls-key.
+    #[structopt(long, env = "DICAST_TLS_CERT", parse(from_os_str))]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate.
+    #[structopt(long, env = "DICAST_TLS_KEY", parse(from_os_str))]
+    pub tls_key: Option<PathBuf>,
+    /// Address to redirect plain HTTP to HTTPS from.
+    #[structopt(long, env = "DICAST_TLS_REDIRECT_ADDR")]
+    pub tls_redirect_addr: Option<SocketAddr>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Commands run in place of the server.
+#[derive(Debug, Clone, Copy, PartialEq, StructOpt)]
+pub enum Command {
+    /// Walks the roll history's hash chain and reports the first broken
+    /// link, exiting with an error if there is one.
+    VerifyHistory,
+}
+
+/// The config file, in which every setting is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub addr: Option<SocketAddr>,
+    pub listen: Option<Listen>,
+    pub trusted_proxies: Option<Vec<TrustedProxy>>,
+    pub static_dir: Option<PathBuf>,
+    pub app_js: Option<PathBuf>,
+    pub app_wasm: Option<PathBuf>,
+    pub tables_dir: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub body_limit: Option<u64>,
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub room_idle: Option<u64>,
+    pub deck_idle: Option<u64>,
+    pub simulation_threads: Option<usize>,
+    pub limits: FileLimits,
+    pub rate_limit: FileRateLimit,
+    pub tls: FileTls,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileLimits {
+    pub max_count: Option<i64>,
+    pub max_sides: Option<i64>,
+    pub max_times: Option<i64>,
+    pub max_trials: Option<u64>,
+    pub max_batch: Option<usize>,
+    pub max_dice: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileRateLimit {
+    pub per_minute: Option<u32>,
+    pub burst: Option<u32>,
+    pub room_per_minute: Option<u32>,
+    pub room_burst: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileTls {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub redirect_addr: Option<SocketAddr>,
+}
+
+/// Serving HTTPS, which is done when a certificate and key are given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Where plain HTTP is redirected to HTTPS from, if anywhere.
+    pub redirect_addr: Option<SocketAddr>,
+}
+
+/// Bounds on how much work a single request may ask for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_count: i64,
+    pub max_sides: i64,
+    pub max_times: i64,
+    pub max_trials: u64,
+    pub max_batch: usize,
+    pub max_dice: i64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_count: 1_000_000,
+            max_sides: 1_000_000_000,
+            max_times: 10_000,
+            max_trials: 1_000_000,
+            max_batch: 100,
+            max_dice: 10_000_000,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks a roll, returning how many dice it rolls in all.
+    pub fn check(&self, times: i64, dice: &StdDice) -> Result<i64, ApiError> {
+        let (low, high) = (*dice.range().start(), *dice.range().end());
+        let sides = match high.checked_sub(low).and_then(|sides| sides.checked_add(1)) {
+            Some(sides) if sides >= 1 => sides,
+            _ => {
+                return Err(ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_range",
+                    format!("Dice have no sides between {} and {}.", low, high),
+                ))
+            }
+        };
+        let rolled = times.checked_mul(dice.count()).unwrap_or(i64::MAX);
+
+        if times > self.max_times {
+            Err(limit_exceeded(format!(
+                "Rolls may be repeated at most {} times.",
+                self.max_times
+            )))
+        } else if dice.count() > self.max_count {
+            Err(limit_exceeded(format!(
+                "Rolls may have at most {} dice.",
+                self.max_count
+            )))
+        } else if sides > self.max_sides {
+            Err(limit_exceeded(format!(
+                "Dice may have at most {} sides.",
+                self.max_sides
+            )))
+        } else if rolled > self.max_dice {
+            Err(self.too_many_dice())
+        } else if !totals_fit(dice) {
+            Err(limit_exceeded(
+                "The roll's totals are too large to count.".to_string(),
+            ))
+        } else {
+            Ok(rolled)
+        }
+    }
+
+    /// Checks that a batch's rolls, `rolled` dice in all so far, keep to
+    /// the dice a request may roll.
+    pub fn check_batch_dice(&self, rolled: i64) -> Result<(), ApiError> {
+        if rolled > self.max_dice {
+            Err(self.too_many_dice())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// How many trials to simulate a roll with, keeping to the most trials
+    /// and the most dice a request may roll.
+    pub fn trials(&self, trials: u64, dice: &StdDice) -> u64 {
+        let per_trial = dice.count().max(1) as u64;
+
+        trials
+            .min(self.max_trials)
+            .min(self.max_dice.max(0) as u64 / per_trial)
+    }
+
+    fn too_many_dice(&self) -> ApiError {
+        limit_exceeded(format!(
+            "Requests may roll at most {} dice in all.",
+            self.max_dice
+        ))
+    }
+
+    pub fn check_batch(&self, len: usize) -> Result<(), ApiError> {
+        if len > self.max_batch {
+            Err(limit_exceeded(format!(
+                "Batches may hold at most {} rolls.",
+                self.max_batch
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Whether every total the dice can roll, and every sum on the way to it,
+/// fits in an `i64`.
+fn totals_fit(dice: &StdDice) -> bool {
+    let (low, high) = (*dice.range().start(), *dice.range().end());
+
+    low.checked_abs()
+        .zip(high.checked_abs())
+        .and_then(|(low, high)| dice.count().max(0).checked_mul(low.max(high)))
+        .zip(dice.multiplier().checked_abs())
+        .and_then(|(sum, multiplier)| sum.checked_mul(multiplier))
+        .zip(dice.modifier().checked_abs())
+        .and_then(|(total, modifier)| total.checked_add(modifier))
+        .is_some()
+}
+
+fn limit_exceeded(message: String) -> ApiError {
+    ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "limit_exceeded", message)
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: Listen,
+    /// The proxies whose forwarding headers give the client's IP.
+    pub trusted_proxies: Vec<TrustedProxy>,
+    // The frontend paths go unused when it is embedded.
+    #[cfg_attr(feature = "embed", allow(dead_code))]
+    pub static_dir: PathBuf,
+    #[cfg_attr(feature = "embed", allow(dead_code))]
+    pub app_js: PathBuf,
+    #[cfg_attr(feature = "embed", allow(dead_code))]
+    pub app_wasm: PathBuf,
+    pub tables_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub body_limit: u64,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub room_idle: Duration,
+    pub deck_idle: Duration,
+    pub simulation_threads: usize,
+    pub limits: Limits,
+    /// How fast each client IP may make requests.
+    pub rate_limit: Rate,
+    /// How fast each room may roll.
+    pub room_rate_limit: Rate,
+    pub tls: Option<Tls>,
+    pub command: Option<Command>,
+}
+
+/// An idle time in seconds. Idle things are looked for every so often, which
+/// can't be every 0 seconds.
+fn idle(name: &'static str, secs: u64) -> Result<Duration, ConfigError> {
+    match secs {
+        0 => Err(ConfigError::ZeroIdle(name)),
+        secs => Ok(Duration::from_secs(secs)),
+    }
+}
+
+/// One simulation thread per CPU keeps simulations from crowding out the
+/// rest of the server.
+fn max_simulation_threads() -> usize {
+    thread::available_parallelism().map_or(SIMULATION_THREADS, NonZeroUsize::get)
+}
+
+impl Config {
+    /// Reads the command line, the environment and the config file, if
+    /// one is given, in that order of precedence.
+    pub fn load() -> Result<Config, ConfigError> {
+        let opt = Opt::from_args();
+
+        let file = match &opt.config {
+            Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+            None => FileConfig::default(),
+        };
+
+        Config::merge(opt, file)
+    }
+
+    fn merge(opt: Opt, file: FileConfig) -> Result<Config, ConfigError> {
+        let defaults = Limits::default();
+
+        let tls = match (opt.tls_cert.or(file.tls.cert), opt.tls_key.or(file.tls.key)) {
+            (Some(cert), Some(key)) => Some(Tls {
+                cert,
+                key,
+                redirect_addr: opt.tls_redirect_addr.or(file.tls.redirect_addr),
+            }),
+            (None, None) => None,
+            _ => return Err(ConfigError::IncompleteTls),
+        };
+
+        Ok(Config {
+            listen: opt
+                .listen
+                .or(opt.addr.map(Listen::Tcp))
+                .or(file.listen)
+                .or(file.addr.map(Listen::Tcp))
+                .unwrap_or_else(|| Listen::Tcp(ADDR.parse().unwrap())),
+            trusted_proxies: match opt.trusted_proxies {
+                trusted if trusted.is_empty() => file.trusted_proxies.unwrap_or_default(),
+                trusted => trusted,
+            },
+            static_dir: opt
+                .static_dir
+                .or(file.static_dir)
+                .unwrap_or_else(|| STATIC_DIR_PATH.into()),
+            app_js: opt.app_js.or(file.app_js).unwrap_or_else(|| APP_JS.into()),
+            app_wasm: opt
+                .app_wasm
+                .or(file.app_wasm)
+                .unwrap_or_else(|| APP_WASM.into()),
+            tables_dir: opt
+                .tables_dir
+                .or(file.tables_dir)
+                .unwrap_or_else(|| TABLES_DIR_PATH.into()),
+            data_dir: opt
+                .data_dir
+                .or(file.data_dir)
+                .unwrap_or_else(|| DATA_DIR_PATH.into()),
+            body_limit: opt.body_limit.or(file.body_limit).unwrap_or(BODY_LIMIT),
+            log_level: opt
+                .log_level
+                .or(file.log_level)
+                .unwrap_or_else(|| LOG_LEVEL.to_string()),
+            log_format: opt
+                .log_format
+                .or(file.log_format)
+                .unwrap_or(LogFormat::Text),
+            room_idle: idle(
+                "room_idle",
+                opt.room_idle.or(file.room_idle).unwrap_or(ROOM_IDLE_SECS),
+            )?,
+            deck_idle: idle(
+                "deck_idle",
+                opt.deck_idle.or(file.deck_idle).unwrap_or(DECK_IDLE_SECS),
+            )?,
+            simulation_threads: opt
+                .simulation_threads
+                .or(file.simulation_threads)
+                .unwrap_or(SIMULATION_THREADS)
+                .min(max_simulation_threads())
+                .max(1),
+            limits: Limits {
+                max_count: opt
+                    .max_count
+                    .or(file.limits.max_count)
+                    .unwrap_or(defaults.max_count),
+                max_sides: opt
+                    .max_sides
+                    .or(file.limits.max_sides)
+                    .unwrap_or(defaults.max_sides),
+                max_times: opt
+                    .max_times
+                    .or(file.limits.max_times)
+                    .unwrap_or(defaults.max_times),
+                max_trials: opt
+                    .max_trials
+                    .or(file.limits.max_trials)
+                    .unwrap_or(defaults.max_trials),
+                max_batch: opt
+                    .max_batch
+                    .or(file.limits.max_batch)
+                    .unwrap_or(defaults.max_batch),
+                max_dice: opt
+                    .max_dice
+                    .or(file.limits.max_dice)
+                    .unwrap_or(defaults.max_dice),
+            },
+            rate_limit: Rate {
+                per_minute: opt
+                    .rate_limit
+                    .or(file.rate_limit.per_minute)
+                    .unwrap_or(RATE_LIMIT.per_minute),
+                burst: opt
+                    .rate_burst
+                    .or(file.rate_limit.burst)
+                    .unwrap_or(RATE_LIMIT.burst),
+            },
+            room_rate_limit: Rate {
+                per_minute: opt
+                    .room_rate_limit
+                    .or(file.rate_limit.room_per_minute)
+                    .unwrap_or(ROOM_RATE_LIMIT.per_minute),
+                burst: opt
+                    .room_rate_burst
+                    .or(file.rate_limit.room_burst)
+                    .unwrap_or(ROOM_RATE_LIMIT.burst),
+            },
+            tls,
+            command: opt.command,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Could not read config file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid config file: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Serving HTTPS needs both a certificate and a key.")]
+    IncompleteTls,
+    #[error("{0} must be at least 1 second.")]
+    ZeroIdle(&'static str),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use dice::parse::parse_str;
+
+    #[test]
+    fn config_defaults() {
+        let config = Config::merge(Opt::default(), FileConfig::default()).unwrap();
+
+        assert_eq!(Listen::Tcp(ADDR.parse().unwrap()), config.listen);
+        assert_eq!(BODY_LIMIT, config.body_limit);
+        assert_eq!(Limits::default(), config.limits);
+        assert_eq!(RATE_LIMIT, config.rate_limit);
+    }
+
+    #[test]
+    fn config_rejects_zero_idle() {
+        let file: FileConfig = toml::from_str("room_idle = 0\n").unwrap();
+        match Config::merge(Opt::default(), file) {
+            Err(ConfigError::ZeroIdle(name)) => assert_eq!("room_idle", name),
+            other => panic!("expected ZeroIdle, got {:?}", other.map(|_| ())),
+        }
+
+        let opt = Opt {
+            deck_idle: Some(0),
+            ..Default::default()
+        };
+        match Config::merge(opt, FileConfig::default()) {
+            Err(ConfigError::ZeroIdle(name)) => assert_eq!("deck_idle", name),
+            other => panic!("expected ZeroIdle, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn config_bounds_simulation_threads() {
+        let opt = Opt {
+            simulation_threads: Some(usize::MAX),
+            ..Default::default()
+        };
+        let config = Config::merge(opt, FileConfig::default()).unwrap();
+        assert_eq!(max_simulation_threads(), config.simulation_threads);
+
+        let opt = Opt {
+            simulation_threads: Some(0),
+            ..Default::default()
+        };
+        let config = Config::merge(opt, FileConfig::default()).unwrap();
+        assert_eq!(1, config.simulation_threads);
+    }
+
+    #[test]
+    fn config_precedence() {
+        let file: FileConfig = toml::from_str(
+            "addr = \"127.0.0.1:4000\"\nbody_limit = 10\n[limits]\nmax_times = 5\n\
+             [rate_limit]\nper_minute = 30\nroom_burst = 2\n",
+        )
+        .unwrap();
+        let opt = Opt {
+            addr: Some("127.0.0.1:5000".parse().unwrap()),
+            rate_limit: Some(0),
+            ..Default::default()
+        };
+
+        let config = Config::merge(opt, file).unwrap();
+
+        assert_eq!(Listen::Tcp("127.0.0.1:5000".parse().unwrap()), config.listen);
+        assert_eq!(10, config.body_limit);
+        assert_eq!(5, config.limits.max_times);
+        assert_eq!(Limits::default().max_count, config.limits.max_count);
+        assert_eq!(0, config.rate_limit.per_minute);
+        assert_eq!(RATE_LIMIT.burst, config.rate_limit.burst);
+        assert_eq!(2, config.room_rate_limit.burst);
+    }
+
+    #[test]
+    fn config_listen() {
+        let file: FileConfig = toml::from_str("listen = \"unix:/run/dicast.sock\"\n").unwrap();
+        let config = Config::merge(Opt::default(), file).unwrap();
+        assert_eq!(Listen::Unix("/run/dicast.sock".into()), config.listen);
+
+        let file: FileConfig = toml::from_str("listen = \"unix:/run/dicast.sock\"\n").unwrap();
+        let opt = Opt {
+            addr: Some("127.0.0.1:5000".parse().unwrap()),
+            ..Default::default()
+        };
+        let config = Config::merge(opt, file).unwrap();
+        assert_eq!(Listen::Tcp("127.0.0.1:5000".parse().unwrap()), config.listen);
+
+        let opt = Opt {
+            addr: Some("127.0.0.1:5000".parse().unwrap()),
+            listen: Some(Listen::Systemd),
+            ..Default::default()
+        };
+        let config = Config::merge(opt, FileConfig::default()).unwrap();
+        assert_eq!(Listen::Systemd, config.listen);
+
+        assert!(toml::from_str::<FileConfig>("listen = \"unix:\"\n").is_err());
+    }
+
+    #[test]
+    fn config_trusted_proxies() {
+        let file: FileConfig =
+            toml::from_str("trusted_proxies = [\"unix\", \"10.0.0.1\"]\n").unwrap();
+        let config = Config::merge(Opt::default(), file).unwrap();
+        assert_eq!(
+            vec![TrustedProxy::Unix, TrustedProxy::Ip("10.0.0.1".parse().unwrap())],
+            config.trusted_proxies
+        );
+
+        let file: FileConfig = toml::from_str("trusted_proxies = [\"unix\"]\n").unwrap();
+        let opt = Opt {
+            trusted_proxies: vec![TrustedProxy::Ip("::1".parse().unwrap())],
+            ..Default::default()
+        };
+        let config = Config::merge(opt, file).unwrap();
+        assert_eq!(vec![TrustedProxy::Ip("::1".parse().unwrap())], config.trusted_proxies);
+
+        assert!(toml::from_str::<FileConfig>("trusted_proxies = [\"10.0.0.0/8\"]\n").is_err());
+    }
+
+    #[test]
+    fn config_tls() {
+        let file: FileConfig = toml::from_str("[tls]\ncert = \"cert.pem\"\n").unwrap();
+        assert!(matches!(
+            Config::merge(Opt::default(), file),
+            Err(ConfigError::IncompleteTls)
+        ));
+
+        let file: FileConfig = toml::from_str("[tls]\ncert = \"cert.pem\"\n").unwrap();
+        let opt = Opt {
+            tls_key: Some("key.pem".into()),
+            ..Default::default()
+        };
+        let tls = Config::merge(opt, file).unwrap().tls.unwrap();
+        assert_eq!(PathBuf::from("cert.pem"), tls.cert);
+        assert_eq!(PathBuf::from("key.pem"), tls.key);
+        assert_eq!(None, tls.redirect_addr);
+    }
+
+    #[test]
+    fn limits_check() {
+        let limits = Limits {
+            max_count: 10,
+            max_sides: 20,
+            max_times: 3,
+            max_trials: 1,
+            max_batch: 2,
+            max_dice: 30,
+        };
+
+        assert!(limits.check(3, &StdDice::new(10, 1..=20, 1, 0, 0)).is_ok());
+        assert!(limits.check(4, &StdDice::new(1, 1..=6, 1, 0, 0)).is_err());
+        assert!(limits.check(1, &StdDice::new(11, 1..=6, 1, 0, 0)).is_err());
+        assert!(limits.check(1, &StdDice::new(1, 1..=21, 1, 0, 0)).is_err());
+        assert!(limits.check_batch(2).is_ok());
+        assert!(limits.check_batch(3).is_err());
+        assert!(limits.check_batch_dice(30).is_ok());
+        assert!(limits.check_batch_dice(31).is_err());
+    }
+
+    #[test]
+    fn limits_check_dice() {
+        let limits = Limits {
+            max_count: 10,
+            max_sides: i64::MAX,
+            max_times: 3,
+            max_trials: 100,
+            max_batch: 2,
+            max_dice: 25,
+        };
+
+        let (times, dice) = parse_str("1d0").unwrap();
+        assert_eq!("invalid_range", limits.check(times, &dice).unwrap_err().body.code);
+
+        assert_eq!(Some(24), limits.check(3, &StdDice::new(8, 1..=6, 1, 0, 0)).ok());
+        assert!(limits.check(3, &StdDice::new(9, 1..=6, 1, 0, 0)).is_err());
+        assert_eq!(12, limits.trials(1_000, &StdDice::new(2, 1..=6, 1, 0, 0)));
+        assert_eq!(5, limits.trials(5, &StdDice::new(2, 1..=6, 1, 0, 0)));
+
+        let huge = StdDice::new(2, 1..=i64::MAX / 2, 1, 0, 0);
+        assert!(limits.check(1, &huge).is_ok());
+        assert!(limits.check(1, &StdDice::new(3, 1..=i64::MAX / 2, 1, 0, 0)).is_err());
+        assert!(limits.check(1, &StdDice::new(1, 1..=6, i64::MAX, 1, 0)).is_err());
+        assert!(limits.check(1, &StdDice::new(1, 1..=6, 1, i64::MAX, 0)).is_err());
+        assert!(limits.check(1, &StdDice::new(1, i64::MIN..=0, 1, 0, 0)).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use rand::Rng;
+use warp::http::StatusCode;
+
+use dice::deck::DiceDeck;
+use dice::dice::{DiceRoller, RollResult, StdDice};
+
+use crate::error::ApiError;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const MAX_SESSIONS: usize = 10_000;
+pub const MAX_DECKS: usize = 10_000;
+/// The most cards held across every deck. Each is eight bytes, so this
+/// keeps decks to about 80MB.
+pub const MAX_CARDS: usize = 10_000_000;
+/// The longest sessions are left unchecked for expiry.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a deal drew, and from which session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deal {
+    pub session: String,
+    pub roll: Vec<RollResult>,
+    /// Cards left before the deck is reshuffled.
+    pub remaining: usize,
+    pub size: usize,
+}
+
+struct Session {
+    /// Keyed by roll, so that each roll has its own deck.
+    decks: HashMap<String, DiceDeck>,
+    last_active: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    sessions: HashMap<String, Session>,
+    decks: usize,
+    cards: usize,
+}
+
+impl Inner {
+    /// Checks that a deal may go ahead, returning whether it needs a new
+    /// deck. A new session is only opened once its deck is built, so that
+    /// a failed first deal leaves nothing behind.
+    fn needs_deck(&self, session: Option<&str>, roll: &str) -> Result<bool, ApiError> {
+        match session.map(|session| self.sessions.get(session)) {
+            Some(Some(open)) if open.decks.contains_key(roll) => return Ok(false),
+            Some(Some(_)) => {}
+            Some(None) => {
+                return Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "unknown_session",
+                    "No such deck session is open.",
+                )
+                .with_hint("Leave out the session to start a new one."))
+            }
+            None if self.sessions.len() >= MAX_SESSIONS => {
+                return Err(too_many("Too many deck sessions are open."))
+            }
+            None => {}
+        }
+
+        if self.decks >= MAX_DECKS {
+            return Err(too_many("Too many decks are in play."));
+        }
+
+        Ok(true)
+    }
+}
+
+/// The decks in play, held in memory. Sessions are issued by the server
+/// on a first deal, and close along with their decks once they have gone
+/// unused for the idle time.
+pub struct Decks {
+    inner: Mutex<Inner>,
+    idle: Duration,
+    max_cards: usize,
+}
+
+impl Decks {
+    pub fn new(idle: Duration) -> Self {
+        Decks {
+            inner: Mutex::new(Inner::default()),
+            idle,
+            max_cards: MAX_CARDS,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().sessions.len()
+    }
+
+    /// Deals from a session's deck for a roll, building the deck if need
+    /// be. Without a session, a new one is opened for the deal.
+    pub fn deal(
+        &self,
+        session: Option<&str>,
+        roll: &str,
+        dice: &StdDice,
+        times: i64,
+        now: Instant,
+    ) -> Result<Deal, ApiError> {
+        // A deck can hold a million cards, so it is built without the lock
+        // held, and the checks are made again once the lock is retaken.
+        let mut built = if self.inner.lock().unwrap().needs_deck(session, roll)? {
+            Some(DiceDeck::new(dice)?)
+        } else {
+            None
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        // Another deal may have added this deck in the meantime, leaving
+        // the one built here unused. Decks only go with their sessions, so
+        // a deck needed now was needed before, and was built.
+        if inner.needs_deck(session, roll)? {
+            let deck = built.as_ref().expect("deck was built");
+            if inner.cards + deck.len() > self.max_cards {
+                return Err(too_many("Too many cards are in play."));
+            }
+        } else {
+            built = None;
+        }
+
+        let session = session.map_or_else(random_hex, str::to_string);
+        let open = inner
+            .sessions
+            .entry(session.clone())
+            .or_insert_with(|| Session {
+                decks: HashMap::new(),
+                last_active: now,
+            });
+        open.last_active = now;
+
+        if let Some(deck) = built {
+            inner.decks += 1;
+            inner.cards += deck.len();
+            open.decks.insert(roll.to_string(), deck);
+        }
+
+        let deck = open.decks.get_mut(roll).expect("deck was built");
+        let roll = DiceRoller::new().deal_times(deck, times);
+
+        Ok(Deal {
+            roll,
+            remaining: deck.remaining(),
+            size: deck.len(),
+            session,
+        })
+    }
+
+    /// Closes sessions that have gone unused for the idle time, returning
+    /// how many were closed.
+    pub fn expire(&self, now: Instant) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let before = inner.sessions.len();
+        let (idle, decks, cards) = (self.idle, &mut inner.decks, &mut inner.cards);
+
+        inner.sessions.retain(|_, session| {
+            let keep = now.saturating_duration_since(session.last_active) < idle;
+            if !keep {
+                *decks -= session.decks.len();
+                *cards -= session.decks.values().map(DiceDeck::len).sum::<usize>();
+            }
+            keep
+        });
+
+        before - inner.sessions.len()
+    }
+}
+
+fn too_many(message: &str) -> ApiError {
+    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "too_many_decks", message)
+}
+
+/// 128 random bits, hex encoded.
+fn random_hex() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 16]>())
+}
+
+/// Closes idle sessions for as long as the server runs.
+pub async fn expire_idle(decks: Arc<Decks>) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL.min(decks.idle));
+
+    loop {
+        interval.tick().await;
+
+        let expired = decks.expire(Instant::now());
+        if expired > 0 {
+            log::info!(
+                "Closed {} idle deck sessions, {} remain open.",
+                expired,
+                decks.len()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use dice::parse::parse_str;
+
+    fn deal(decks: &Decks, session: Option<&str>, now: Instant) -> Result<Deal, ApiError> {
+        let (times, dice) = parse_str("2d6").unwrap();
+        decks.deal(session, "2d6", &dice, times, now)
+    }
+
+    #[test]
+    fn decks_issue_sessions() {
+        let decks = Decks::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        let first = deal(&decks, None, now).unwrap();
+        assert_eq!(36, first.size);
+        assert_eq!(35, first.remaining);
+
+        let second = deal(&decks, Some(&first.session), now).unwrap();
+        assert_eq!(first.session, second.session);
+        assert_eq!(34, second.remaining);
+
+        let other = deal(&decks, None, now).unwrap();
+        assert_ne!(first.session, other.session);
+        assert_eq!(35, other.remaining);
+
+        let err = deal(&decks, Some("made-up"), now).unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, err.status);
+    }
+
+    #[test]
+    fn decks_expire_when_idle() {
+        let decks = Decks::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        let old = deal(&decks, None, now).unwrap();
+        let later = now + Duration::from_secs(30);
+        deal(&decks, None, later).unwrap();
+
+        assert_eq!(1, decks.expire(now + Duration::from_secs(61)));
+        assert_eq!(1, decks.len());
+        assert_eq!(36, decks.inner.lock().unwrap().cards);
+        assert!(deal(&decks, Some(&old.session), later).is_err());
+    }
+
+    #[test]
+    fn decks_keep_to_the_card_budget() {
+        let mut decks = Decks::new(Duration::from_secs(60));
+        decks.max_cards = 100;
+        let now = Instant::now();
+
+        deal(&decks, None, now).unwrap();
+        deal(&decks, None, now).unwrap();
+        let err = deal(&decks, None, now).unwrap_err();
+        assert_eq!("too_many_decks", err.body.code);
+        assert_eq!(2, decks.len());
+    }
+}
@@ -0,0 +1,290 @@
+use serde_derive::{Deserialize, Serialize};
+use warp::http::header::{HeaderValue, RETRY_AFTER};
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use dice::deck::DeckError;
+use dice::parse::ParseError;
+use dice::tables::TableError;
+
+use crate::accounts::AccountsError;
+use crate::history::HistoryError;
+use crate::sets::SetsError;
+
+use std::convert::Infallible;
+use std::error::Error;
+use std::ops::Range;
+
+const SYNTAX_HINT: &str =
+    "Rolls are written as {#x}{#}d{#}{*//#}{+/-#}{s#}, for example `3x 4d6 +1 s1`.";
+const BODY_HINT: &str = "Send a JSON body such as {\"roll\": \"1d20\"}.";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The body of every error response.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ErrorBody {
+    /// A stable, machine readable name for the error.
+    pub code: String,
+    pub message: String,
+    /// The byte range of the roll the error was found at, if any.
+    pub span: Option<Span>,
+    pub hint: Option<String>,
+}
+
+/// An error that a route rejects with, rendered by `handle_rejection`.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub body: ErrorBody,
+    /// Seconds to wait before trying again, sent as `Retry-After`.
+    pub retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &str, message: impl ToString) -> Self {
+        ApiError {
+            status,
+            body: ErrorBody {
+                code: code.to_string(),
+                message: message.to_string(),
+                span: None,
+                hint: None,
+            },
+            retry_after: None,
+        }
+    }
+
+    pub fn with_span(mut self, span: Range<usize>) -> Self {
+        self.body.span = Some(Span {
+            start: span.start,
+            end: span.end,
+        });
+        self
+    }
+
+    pub fn with_hint(mut self, hint: &str) -> Self {
+        self.body.hint = Some(hint.to_string());
+        self
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    pub fn internal() -> Self {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "Something went wrong, apologies.",
+        )
+    }
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl Reply for ApiError {
+    fn into_response(self) -> warp::reply::Response {
+        let mut response =
+            warp::reply::with_status(warp::reply::json(&self.body), self.status).into_response();
+
+        if let Some(secs) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+
+        response
+    }
+}
+
+impl From<ParseError> for ApiError {
+    fn from(e: ParseError) -> Self {
+        let code = match e {
+            ParseError::InvalidToken(..) => "invalid_token",
+            ParseError::UnexpectedToken(..) => "unexpected_token",
+            ParseError::NumberTooLarge(..) => "number_too_large",
+        };
+
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, code, &e)
+            .with_span(e.span())
+            .with_hint(SYNTAX_HINT)
+    }
+}
+
+impl From<TableError> for ApiError {
+    fn from(e: TableError) -> Self {
+        match e {
+            TableError::UnknownTable(_) => ApiError::new(StatusCode::NOT_FOUND, "unknown_table", e),
+            e => {
+                log::error!("Error: {}", e);
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "table_error", e)
+            }
+        }
+    }
+}
+
+impl From<DeckError> for ApiError {
+    fn from(e: DeckError) -> Self {
+        let code = match e {
+            DeckError::InvalidRange(..) => "invalid_range",
+            DeckError::InvalidCount(_) => "invalid_count",
+            DeckError::TooLarge => "deck_too_large",
+        };
+
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, code, e)
+    }
+}
+
+impl From<HistoryError> for ApiError {
+    fn from(e: HistoryError) -> Self {
+        log::error!("Error: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "history_error",
+            "The roll history could not be accessed.",
+        )
+    }
+}
+
+impl From<AccountsError> for ApiError {
+    fn from(e: AccountsError) -> Self {
+        log::error!("Error: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "accounts_error",
+            "The accounts could not be accessed.",
+        )
+    }
+}
+
+impl From<SetsError> for ApiError {
+    fn from(e: SetsError) -> Self {
+        log::error!("Error: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "sets_error",
+            "The dice sets could not be accessed.",
+        )
+    }
+}
+
+impl From<Box<dyn Error>> for ApiError {
+    fn from(e: Box<dyn Error>) -> Self {
+        log::error!("Error: {}", e);
+        ApiError::internal()
+    }
+}
+
+/// Turns any rejection into a JSON `ErrorBody` response.
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    use warp::reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, UnsupportedMediaType,
+    };
+
+    let error = if let Some(e) = err.find::<ApiError>() {
+        e.clone()
+    } else if err.find::<PayloadTooLarge>().is_some() {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            "The request body is too large.",
+        )
+    } else if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_body", e).with_hint(BODY_HINT)
+    } else if err.find::<LengthRequired>().is_some() {
+        ApiError::new(
+            StatusCode::LENGTH_REQUIRED,
+            "length_required",
+            "A content-length header is required.",
+        )
+    } else if err.find::<UnsupportedMediaType>().is_some() {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "The request's content-type is not supported.",
+        )
+        .with_hint(BODY_HINT)
+    } else if let Some(e) = err.find::<InvalidQuery>() {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_query", e)
+    } else if let Some(e) = err.find::<MissingHeader>() {
+        ApiError::new(StatusCode::BAD_REQUEST, "missing_header", e)
+    } else if let Some(e) = err.find::<InvalidHeader>() {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_header", e)
+    } else if err.find::<MethodNotAllowed>().is_some() {
+        ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "HTTP method not allowed.",
+        )
+    } else if err.is_not_found() {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", "Not found.")
+    } else {
+        log::error!("Unhandled rejection: {:?}", err);
+        ApiError::internal()
+    };
+
+    Ok(error)
+}
+
+/// Converts a handler's result into what warp expects, rejecting with an
+/// `ApiError` that `handle_rejection` renders.
+pub trait ForWarp {
+    type Reply;
+
+    fn for_warp(self) -> Result<Self::Reply, Rejection>;
+}
+
+impl<T, E> ForWarp for Result<T, E>
+where
+    T: Reply + 'static,
+    E: Into<ApiError>,
+{
+    type Reply = T;
+
+    fn for_warp(self) -> Result<Self::Reply, Rejection> {
+        self.map_err(|e| warp::reject::custom(e.into()))
+    }
+}
+
+/// Runs work that blocks, such as reading files, on a blocking thread so
+/// that it doesn't hold up the runtime.
+pub async fn blocking<T, F>(f: F) -> Result<T, ApiError>
+where
+    F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| {
+        log::error!("Blocking task failed: {}", e);
+        Err(ApiError::internal())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_error_from_parse_error() {
+        let error = ApiError::from(ParseError::InvalidToken("q".to_string(), 3..4));
+
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, error.status);
+        assert_eq!("invalid_token", error.body.code);
+        assert_eq!(Some(Span { start: 3, end: 4 }), error.body.span);
+        assert!(error.body.hint.is_some());
+    }
+
+    #[tokio::test]
+    async fn handle_rejection_not_found() {
+        let reply = handle_rejection(warp::reject::not_found()).await.unwrap();
+        let response = reply.into_response();
+
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+    }
+}
@@ -0,0 +1,398 @@
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use dice::dice::{DiceRoller, StdDice};
+
+use crate::config::Limits;
+use crate::error::{blocking, ApiError, ForWarp};
+use crate::history::{Entry, FairRoll, History};
+use crate::metrics;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a committed seed waits to be rolled with.
+const SEED_TTL: Duration = Duration::from_secs(60 * 60);
+const MAX_SEEDS: usize = 10_000;
+/// How many seeds each client IP may have waiting, so that no one client
+/// can take them all.
+const MAX_SEEDS_PER_CLIENT: usize = 100;
+const MAX_CLIENT_SEED_LEN: usize = 256;
+
+/// A server seed waiting to be rolled with.
+#[derive(Debug)]
+struct Pending {
+    seed: String,
+    created: Instant,
+    /// Who asked for it. Clients whose IP isn't known share a limit.
+    client: Option<IpAddr>,
+}
+
+/// Server seeds that have been committed to but not yet rolled with,
+/// keyed by their commitments.
+#[derive(Debug, Default)]
+pub struct Seeds {
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+impl Seeds {
+    pub fn new() -> Seeds {
+        Seeds::default()
+    }
+
+    /// Makes a new server seed for a client, returning the commitment to
+    /// it.
+    pub fn commit(&self, client: Option<IpAddr>, now: Instant) -> Result<String, ApiError> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, pending| now.duration_since(pending.created) < SEED_TTL);
+
+        let waiting = pending
+            .values()
+            .filter(|pending| pending.client == client)
+            .count();
+        if waiting >= MAX_SEEDS_PER_CLIENT {
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too_many_seeds",
+                format!(
+                    "At most {} seeds may wait to be rolled with at once.",
+                    MAX_SEEDS_PER_CLIENT
+                ),
+            )
+            .with_hint("Roll with the seeds you have, or wait for them to expire."));
+        }
+        if pending.len() >= MAX_SEEDS {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too_many_seeds",
+                "Too many seeds are waiting to be rolled with.",
+            ));
+        }
+
+        let seed = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        let commitment = commitment(&seed);
+        pending.insert(
+            commitment.clone(),
+            Pending {
+                seed,
+                created: now,
+                client,
+            },
+        );
+
+        Ok(commitment)
+    }
+
+    /// Takes the seed behind a commitment. Each seed rolls once, so that
+    /// it can't be rerolled until it gives a result the server likes.
+    pub fn take(&self, commitment: &str, now: Instant) -> Result<String, ApiError> {
+        let mut pending = self.pending.lock().unwrap();
+
+        match pending.remove(commitment) {
+            Some(pending) if now.duration_since(pending.created) < SEED_TTL => Ok(pending.seed),
+            _ => Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "unknown_commitment",
+                "No seed is waiting under that commitment.",
+            )
+            .with_hint("Get a new one from `POST /fair/commit`.")),
+        }
+    }
+}
+
+/// The hex SHA-256 of a server seed.
+pub fn commitment(server_seed: &str) -> String {
+    hex::encode(Sha256::digest(server_seed.as_bytes()))
+}
+
+/// Rolls with a roller seeded by `sha256(server_seed + ":" + client_seed)`,
+/// so that the same seeds and dice always give the same results.
+pub fn roll(server_seed: &str, client_seed: &str, times: i64, dice: &StdDice) -> Vec<i64> {
+    let seed = Sha256::new()
+        .chain(server_seed)
+        .chain(":")
+        .chain(client_seed)
+        .finalize();
+    let mut roller = DiceRoller::seeded(seed.into());
+
+    roller.roll_dice_times(dice, times)
+}
+
+/// Parses and checks a roll and the client seed it is to be rolled with.
+fn parse(client_seed: &str, roll: &str, limits: &Limits) -> Result<(i64, StdDice), ApiError> {
+    if client_seed.chars().count() > MAX_CLIENT_SEED_LEN {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_seed",
+            format!(
+                "Client seeds are at most {} characters.",
+                MAX_CLIENT_SEED_LEN
+            ),
+        ));
+    }
+
+    let (times, dice) = metrics::parse(roll)?;
+    limits.check(times, &dice)?;
+
+    Ok((times, dice))
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct CommitResponse {
+    commitment: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FairRollRequest {
+    commitment: String,
+    #[serde(default)]
+    client_seed: String,
+    roll: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FairRollResponse {
+    roll: Vec<i64>,
+    commitment: String,
+    server_seed: String,
+    client_seed: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct VerifyRequest {
+    server_seed: String,
+    #[serde(default)]
+    client_seed: String,
+    roll: String,
+    result: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct VerifyResponse {
+    valid: bool,
+    commitment: String,
+    result: Vec<i64>,
+}
+
+/// `POST /fair/commit`, `POST /fair/roll` and `POST /verify`.
+pub fn routes(
+    seeds: Arc<Seeds>,
+    history: Arc<History>,
+    limits: Limits,
+    body_limit: u64,
+) -> BoxedFilter<(Response,)> {
+    let with_seeds = warp::any().map(move || seeds.clone());
+    let with_history = warp::any().map(move || history.clone());
+
+    let commit = warp::filters::method::post()
+        .and(warp::path!("fair" / "commit"))
+        .and(crate::proxy::client_ip())
+        .and(with_seeds.clone())
+        .and_then(|client: Option<IpAddr>, seeds: Arc<Seeds>| async move {
+            let commitment = seeds.commit(client, Instant::now());
+            commitment
+                .map(|commitment| warp::reply::json(&CommitResponse { commitment }).into_response())
+                .for_warp()
+        });
+
+    let fair_roll = warp::filters::method::post()
+        .and(warp::path!("fair" / "roll"))
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::json())
+        .and(with_seeds)
+        .and(with_history)
+        .and_then(
+            move |req: FairRollRequest, seeds: Arc<Seeds>, history: Arc<History>| async move {
+                blocking(move || roll_fair(req, &seeds, &history, &limits))
+                    .await
+                    .for_warp()
+            },
+        );
+
+    let verify = warp::filters::method::post()
+        .and(warp::path("verify"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(body_limit))
+        .and(warp::body::json())
+        .and_then(move |req: VerifyRequest| async move {
+            blocking(move || verify(req, &limits)).await.for_warp()
+        });
+
+    commit.or(fair_roll).unify().or(verify).unify().boxed()
+}
+
+/// Rolls with a committed seed. A roll can be as large as the limits
+/// allow, so this and `verify` are run on a blocking thread.
+fn roll_fair(
+    req: FairRollRequest,
+    seeds: &Seeds,
+    history: &History,
+    limits: &Limits,
+) -> Result<Response, ApiError> {
+    log::info!("Received a fair request: {:?}", req.roll);
+
+    // Check the roll before spending the seed on it.
+    let (times, dice) = parse(&req.client_seed, &req.roll, limits)?;
+    let server_seed = seeds.take(&req.commitment, Instant::now())?;
+    let result = roll(&server_seed, &req.client_seed, times, &dice);
+
+    history.record(Entry {
+        roll: req.roll,
+        totals: result.clone(),
+        fair: Some(FairRoll {
+            commitment: req.commitment.clone(),
+            server_seed: server_seed.clone(),
+            client_seed: req.client_seed.clone(),
+        }),
+        ..Entry::default()
+    })?;
+
+    Ok(warp::reply::json(&FairRollResponse {
+        roll: result,
+        commitment: req.commitment,
+        server_seed,
+        client_seed: req.client_seed,
+    })
+    .into_response())
+}
+
+fn verify(req: VerifyRequest, limits: &Limits) -> Result<Response, ApiError> {
+    let (times, dice) = parse(&req.client_seed, &req.roll, limits)?;
+    let result = roll(&req.server_seed, &req.client_seed, times, &dice);
+
+    Ok(warp::reply::json(&VerifyResponse {
+        valid: result == req.result,
+        commitment: commitment(&req.server_seed),
+        result,
+    })
+    .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{json, Value};
+
+    #[test]
+    fn seeds_commit_and_take() {
+        let seeds = Seeds::new();
+        let now = Instant::now();
+
+        let first = seeds.commit(None, now).unwrap();
+        let second = seeds.commit(None, now).unwrap();
+        assert_ne!(first, second);
+
+        let seed = seeds.take(&first, now).unwrap();
+        assert_eq!(first, commitment(&seed));
+        assert_eq!(
+            "unknown_commitment",
+            seeds.take(&first, now).unwrap_err().body.code
+        );
+
+        let later = now + SEED_TTL;
+        assert!(seeds.take(&second, later).is_err());
+    }
+
+    #[test]
+    fn seeds_are_capped_per_client() {
+        let seeds = Seeds::new();
+        let now = Instant::now();
+        let greedy = Some("203.0.113.7".parse().unwrap());
+
+        for _ in 0..MAX_SEEDS_PER_CLIENT {
+            seeds.commit(greedy, now).unwrap();
+        }
+        let error = seeds.commit(greedy, now).unwrap_err();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, error.status);
+
+        // Others can still get seeds, and the greedy client can once its
+        // seeds expire.
+        seeds
+            .commit(Some("203.0.113.8".parse().unwrap()), now)
+            .unwrap();
+        seeds.commit(greedy, now + SEED_TTL).unwrap();
+    }
+
+    #[test]
+    fn roll_is_reproducible() {
+        let limits = Limits::default();
+        let (times, dice) = parse("client", "10x 4d6 s1", &limits).unwrap();
+
+        let roll_0 = roll("server", "client", times, &dice);
+        let roll_1 = roll("server", "client", times, &dice);
+        let roll_2 = roll("server", "other", times, &dice);
+
+        assert_eq!(10, roll_0.len());
+        assert_eq!(roll_0, roll_1);
+        assert_ne!(roll_0, roll_2);
+
+        let long = "x".repeat(MAX_CLIENT_SEED_LEN + 1);
+        let error = parse(&long, "1d6", &limits).unwrap_err();
+        assert_eq!("invalid_seed", error.body.code);
+    }
+
+    #[tokio::test]
+    async fn fair_roll_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = Arc::new(History::open(dir.path()).unwrap());
+        let routes = routes(
+            Arc::new(Seeds::new()),
+            history.clone(),
+            Limits::default(),
+            1024,
+        );
+
+        let post = |path: &str, body: Value| {
+            warp::test::request()
+                .method("POST")
+                .path(path)
+                .json(&body)
+                .reply(&routes)
+        };
+        let body = |response: warp::http::Response<warp::hyper::body::Bytes>| {
+            serde_json::from_slice::<Value>(response.body()).unwrap()
+        };
+
+        let commit = body(post("/fair/commit", json!({})).await);
+        let request = json!({
+            "commitment": commit["commitment"],
+            "client_seed": "lucky",
+            "roll": "3x 1d20",
+        });
+        let rolled = body(post("/fair/roll", request).await);
+        assert_eq!(commit["commitment"], rolled["commitment"]);
+        assert_eq!(
+            rolled["commitment"],
+            commitment(rolled["server_seed"].as_str().unwrap()).as_str()
+        );
+
+        let request = json!({
+            "server_seed": rolled["server_seed"],
+            "client_seed": "lucky",
+            "roll": "3x 1d20",
+            "result": rolled["roll"],
+        });
+        let verified = body(post("/verify", request.clone()).await);
+        assert_eq!(true, verified["valid"]);
+        assert_eq!(rolled["commitment"], verified["commitment"]);
+
+        let mut forged = request;
+        forged["result"] = json!([0, 0, 0]);
+        assert_eq!(false, body(post("/verify", forged).await)["valid"]);
+
+        // The history keeps what is needed to verify the roll.
+        let page = history.query(&Default::default()).unwrap();
+        let fair = page.records[0].fair.as_ref().unwrap();
+        assert_eq!(rolled["commitment"], fair.commitment.as_str());
+        assert_eq!(rolled["server_seed"], fair.server_seed.as_str());
+        assert_eq!("lucky", fair.client_seed);
+    }
+}
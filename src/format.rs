@@ -0,0 +1,188 @@
+use warp::http::header::{HeaderValue, CACHE_CONTROL, VARY};
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+
+use crate::error::ApiError;
+use crate::mime::Mime;
+
+/// How a roll is written out, chosen from the `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Markdown,
+}
+
+impl Format {
+    fn mime(self) -> Mime {
+        match self {
+            Format::Text => Mime::Text,
+            Format::Json => Mime::Json,
+            Format::Markdown => Mime::Markdown,
+        }
+    }
+
+    /// Picks the format an `Accept` header weighs highest, preferring them
+    /// in the order text, JSON, Markdown on ties. Plain text is used when
+    /// the header is missing or accepts none of them.
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Format::Text,
+        };
+
+        let ranges = accept
+            .split(',')
+            .filter_map(|part| {
+                let mut params = part.split(';').map(str::trim);
+                let range = params.next().filter(|range| !range.is_empty())?;
+                let q = params
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((range.to_ascii_lowercase(), q))
+            })
+            .collect::<Vec<_>>();
+
+        // The weight of a format is that of the most specific range that
+        // matches it.
+        let weight = |format: Format| {
+            let essence = format.mime().to_string();
+            let essence = essence.split(';').next().unwrap_or_default();
+            let wildcard = format!("{}/*", essence.split('/').next().unwrap_or_default());
+
+            [essence, wildcard.as_str(), "*/*"]
+                .iter()
+                .find_map(|pattern| {
+                    ranges
+                        .iter()
+                        .find(|(range, _)| range == pattern)
+                        .map(|&(_, q)| q)
+                })
+                .unwrap_or(0.0)
+        };
+
+        [Format::Text, Format::Json, Format::Markdown]
+            .iter()
+            .map(|&format| (format, weight(format)))
+            .filter(|&(_, q)| q > 0.0)
+            .fold(
+                None,
+                |best: Option<(Format, f32)>, (format, q)| match best {
+                    Some((_, best_q)) if best_q >= q => best,
+                    _ => Some((format, q)),
+                },
+            )
+            .map(|(format, _)| format)
+            .unwrap_or(Format::Text)
+    }
+
+    /// Writes out a roll, or the error it failed with. JSON errors are left
+    /// to `handle_rejection`, so they match every other route.
+    pub fn render(
+        self,
+        expr: &str,
+        result: Result<Vec<i64>, ApiError>,
+    ) -> Result<Response, ApiError> {
+        let (status, body) = match (self, result) {
+            (Format::Json, result) => {
+                let reply = warp::reply::json(&serde_json::json!({ "roll": result? }));
+                return Ok(uncached(reply.into_response()));
+            }
+            (Format::Text, Ok(roll)) => (StatusCode::OK, format!("{}\n", join(&roll, " "))),
+            (Format::Markdown, Ok(roll)) => (
+                StatusCode::OK,
+                format!("`{}`: **{}**\n", expr, join(&roll, "**, **")),
+            ),
+            (Format::Text, Err(e)) => (e.status, format!("{}\n", e.body.message)),
+            (Format::Markdown, Err(e)) => (e.status, format!("`{}`: {}\n", expr, e.body.message)),
+        };
+
+        let reply = warp::reply::with_header(body, "content-type", self.mime().to_string());
+
+        let response = warp::reply::with_status(reply, status).into_response();
+
+        Ok(uncached(response))
+    }
+}
+
+/// Every roll is a new one, and how it is written depends on `Accept`, so
+/// caches may neither keep it nor hand it to a client accepting another
+/// format.
+fn uncached(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(VARY, HeaderValue::from_static("accept"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+    response
+}
+
+fn join(roll: &[i64], separator: &str) -> String {
+    roll.iter()
+        .map(i64::to_string)
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_negotiate() {
+        use Format::*;
+
+        assert_eq!(Text, Format::negotiate(None));
+        assert_eq!(Text, Format::negotiate(Some("*/*")));
+        assert_eq!(Json, Format::negotiate(Some("application/json")));
+        assert_eq!(Markdown, Format::negotiate(Some("text/markdown")));
+        assert_eq!(Text, Format::negotiate(Some("text/*")));
+        assert_eq!(
+            Json,
+            Format::negotiate(Some("text/plain;q=0.5, application/json"))
+        );
+        assert_eq!(
+            Markdown,
+            Format::negotiate(Some("text/markdown, text/*;q=0.2, */*;q=0.1"))
+        );
+        assert_eq!(Text, Format::negotiate(Some("image/png")));
+    }
+
+    #[test]
+    fn format_render() {
+        let response = Format::Text.render("2x1d6", Ok(vec![3, 5])).unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(
+            "text/plain; charset=utf-8",
+            response.headers()["content-type"]
+        );
+        assert_eq!("accept", response.headers()["vary"]);
+        assert_eq!("no-store", response.headers()["cache-control"]);
+
+        let response = Format::Json.render("1d6", Ok(vec![4])).unwrap();
+        assert_eq!("accept", response.headers()["vary"]);
+        assert_eq!("no-store", response.headers()["cache-control"]);
+
+        let response = Format::Markdown
+            .render(
+                "1q",
+                Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "x", "bad")),
+            )
+            .unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, response.status());
+        assert_eq!("accept", response.headers()["vary"]);
+
+        assert!(Format::Json
+            .render(
+                "1q",
+                Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "x", "bad"))
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn join_rolls() {
+        assert_eq!("17", join(&[17], " "));
+        assert_eq!("3**, **5", join(&[3, 5], "**, **"));
+    }
+}
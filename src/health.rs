@@ -0,0 +1,109 @@
+use serde_derive::Serialize;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::error::{ApiError, ForWarp};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Serialize)]
+struct Status {
+    status: &'static str,
+}
+
+/// Whether the server should be sent traffic. It stops being ready once
+/// it starts shutting down, so that load balancers move away from it.
+#[derive(Debug, Default)]
+pub struct Health {
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health::default()
+    }
+
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn ready(&self) -> Result<(), ApiError> {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "shutting_down",
+                "The server is shutting down.",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Waits for SIGINT or, on Unix, SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut term = signal(SignalKind::terminate()).expect("SIGTERM can be handled");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// `GET /healthz`, answered while the server runs at all, and
+/// `GET /readyz`, answered with 503 once it is shutting down.
+pub fn routes(health: Arc<Health>) -> BoxedFilter<(Response,)> {
+    let healthz = warp::filters::method::get()
+        .and(warp::path("healthz"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&Status { status: "ok" }).into_response());
+
+    let readyz = warp::filters::method::get()
+        .and(warp::path("readyz"))
+        .and(warp::path::end())
+        .and_then(move || {
+            let ready = health
+                .ready()
+                .map(|()| warp::reply::json(&Status { status: "ready" }).into_response());
+
+            async move { ready.for_warp() }
+        });
+
+    healthz.or(readyz).unify().boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn readyz_fails_when_shutting_down() {
+        let health = Arc::new(Health::new());
+        let routes = routes(health.clone()).recover(crate::error::handle_rejection);
+
+        let status = |path| warp::test::request().path(path).reply(&routes);
+
+        assert_eq!(StatusCode::OK, status("/healthz").await.status());
+        assert_eq!(StatusCode::OK, status("/readyz").await.status());
+
+        health.shut_down();
+
+        assert_eq!(StatusCode::OK, status("/healthz").await.status());
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            status("/readyz").await.status()
+        );
+    }
+}
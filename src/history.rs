@@ -0,0 +1,844 @@
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use dice::dice::{Breakdown, DiceRoller, StdDice};
+use dice::tables::TableRoll;
+
+use crate::rooms::Visibility;
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HISTORY_FILE: &str = "history.jsonl";
+/// What the first record in a history points back to.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+/// Rolls with more dice than this are recorded by their totals alone.
+pub const MAX_BREAKDOWN_DICE: i64 = 1_000;
+const DEFAULT_PAGE: usize = 50;
+const MAX_PAGE: usize = 500;
+
+/// A roll as it is kept on record.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Record {
+    pub id: u64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub roll: String,
+    pub totals: Vec<i64>,
+    /// Every die rolled, for rolls small enough to keep them.
+    pub breakdown: Option<Vec<Breakdown>>,
+    pub room: Option<String>,
+    pub user: Option<String>,
+    pub visibility: Visibility,
+    /// The hex SHA-256 of the line before this one, chaining every record
+    /// to those before it. Missing from records written before chaining.
+    #[serde(default)]
+    pub prev: Option<String>,
+    /// For a roll on a random table, what it landed on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table: Option<TableRoll>,
+    /// For a hidden roll, the commitment players were shown in its place.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commitment: Option<String>,
+    /// For a reveal, the hidden roll it reveals. Reveals hold no totals of
+    /// their own, so that the roll is only counted once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reveal: Option<Reveal>,
+    /// For a provably fair roll, the seeds it was rolled with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fair: Option<FairRoll>,
+}
+
+/// A hidden roll being revealed.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Reveal {
+    /// The id of the hidden roll's record.
+    pub id: u64,
+    pub commitment: String,
+    /// What the commitment was salted with, so it can be checked against
+    /// the hidden roll's dice.
+    pub salt: String,
+}
+
+/// The seeds of a provably fair roll, so that it can be verified from the
+/// history alone.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FairRoll {
+    /// The commitment to the server seed, given out before the roll.
+    pub commitment: String,
+    pub server_seed: String,
+    pub client_seed: String,
+}
+
+/// The parts of a record that callers fill in.
+#[derive(Debug, Clone, Default)]
+pub struct Entry {
+    pub roll: String,
+    pub totals: Vec<i64>,
+    pub breakdown: Option<Vec<Breakdown>>,
+    pub room: Option<String>,
+    pub user: Option<String>,
+    pub visibility: Visibility,
+    pub table: Option<TableRoll>,
+    pub commitment: Option<String>,
+    pub reveal: Option<Reveal>,
+    pub fair: Option<FairRoll>,
+}
+
+impl Entry {
+    /// Rolls the dice, keeping every die when there are few enough.
+    pub fn roll(roll: &str, times: i64, dice: &StdDice) -> Entry {
+        let mut roller = DiceRoller::new();
+
+        let count = times.max(0).saturating_mul(dice.count());
+
+        let (totals, breakdown) = if count <= MAX_BREAKDOWN_DICE {
+            let breakdown = roller.roll_breakdown_times(dice, times);
+            let totals = breakdown.iter().map(|b| b.total).collect();
+
+            (totals, Some(breakdown))
+        } else {
+            (roller.roll_dice_times(dice, times), None)
+        };
+
+        Entry {
+            roll: roll.to_string(),
+            totals,
+            breakdown,
+            ..Entry::default()
+        }
+    }
+
+    /// A roll on a random table, recorded as `table:{name}` with the
+    /// number rolled on it.
+    pub fn table(roll: &TableRoll) -> Entry {
+        Entry {
+            roll: format!("table:{}", roll.table),
+            totals: vec![roll.roll],
+            table: Some(roll.clone()),
+            ..Entry::default()
+        }
+    }
+}
+
+/// Which records to return from `History::query`, newest first.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Query {
+    pub room: Option<String>,
+    pub user: Option<String>,
+    pub roll: Option<String>,
+    /// Milliseconds since the Unix epoch, inclusive.
+    pub from: Option<u64>,
+    /// Milliseconds since the Unix epoch, exclusive.
+    pub to: Option<u64>,
+    /// Only records older than this id, for paging.
+    pub before: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    /// Whether a record is listed. Hidden rolls are once they are revealed,
+    /// in place of their reveals.
+    fn matches(&self, record: &Record, revealed: &HashSet<u64>) -> bool {
+        let listed = match record.visibility {
+            Visibility::Public => record.reveal.is_none(),
+            Visibility::Gm | Visibility::Blind => revealed.contains(&record.id),
+        };
+
+        listed
+            && self.before.map_or(true, |before| record.id < before)
+            && self.from.map_or(true, |from| record.timestamp >= from)
+            && self.to.map_or(true, |to| record.timestamp < to)
+            && (self.room.is_none() || self.room == record.room)
+            && (self.user.is_none() || self.user == record.user)
+            && self.roll.as_ref().map_or(true, |roll| roll == &record.roll)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Page {
+    pub records: Vec<Record>,
+    /// Pass as `before` to get the next page, if there is one.
+    pub next: Option<u64>,
+}
+
+/// The outcome of walking a history's hash chain.
+#[derive(Debug, Clone, Serialize)]
+pub struct Verification {
+    pub valid: bool,
+    /// Records checked, up to the first broken link.
+    pub records: u64,
+    /// Lines that could not be read, such as one cut short by a crash.
+    pub skipped: u64,
+    /// Records with no `prev`, from before records were chained. Nothing
+    /// ties them to the records before them, so they make the history
+    /// invalid.
+    pub unchained: u64,
+    /// The hash of the last record checked. Noting it down shows later
+    /// whether records were cut from the end, which the chain can't.
+    pub head: String,
+    pub broken: Option<BrokenLink>,
+}
+
+/// A record that does not point back to the record before it.
+#[derive(Debug, Clone, Serialize)]
+pub struct BrokenLink {
+    /// Counting from 1.
+    pub line: usize,
+    pub id: u64,
+    pub expected: String,
+    pub found: Option<String>,
+}
+
+/// What the writer thread is sent.
+#[derive(Debug)]
+enum Message {
+    Line(Vec<u8>),
+    /// Answered once every line sent before it is written, and synced to
+    /// disk if asked.
+    Flush {
+        sync: bool,
+        done: mpsc::Sender<io::Result<()>>,
+    },
+}
+
+#[derive(Debug)]
+struct Log {
+    writer: mpsc::Sender<Message>,
+    next_id: u64,
+    /// The hash of the last line sent to be written.
+    last_hash: String,
+}
+
+/// Every roll made, appended to a JSON lines file.
+///
+/// Records are numbered and chained as they are made, then written by a
+/// thread of their own, so that recording a roll never waits on the disk.
+/// Queries read the whole file, which keeps memory flat at the cost of
+/// slower queries as the history grows, so they and `verify` block and
+/// are best run on a blocking thread.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    log: Mutex<Log>,
+}
+
+impl History {
+    /// Opens the history in a data directory, creating it if need be.
+    pub fn open(dir: &Path) -> Result<History, HistoryError> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(HISTORY_FILE);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+
+        // End a partial last line left by a crash, so the next record
+        // starts on a line of its own.
+        if file.metadata()?.len() > 0 {
+            let mut last = [0];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+            }
+        }
+
+        let len = file.metadata()?.len();
+        let (next_id, last_hash) = match lines(&path)?.last() {
+            Some((line, record)) => (record.id + 1, hash(&line)),
+            None => (1, GENESIS.to_string()),
+        };
+
+        let (writer, lines) = mpsc::channel();
+        thread::Builder::new()
+            .name("history".to_string())
+            .spawn(move || write_lines(file, len, lines))?;
+
+        Ok(History {
+            path,
+            log: Mutex::new(Log {
+                writer,
+                next_id,
+                last_hash,
+            }),
+        })
+    }
+
+    /// Adds a roll to the history, returning it as recorded. The record is
+    /// written shortly after.
+    pub fn record(&self, entry: Entry) -> Result<Record, HistoryError> {
+        let mut log = self.log.lock().unwrap();
+
+        let record = Record {
+            id: log.next_id,
+            timestamp: now(),
+            roll: entry.roll,
+            totals: entry.totals,
+            breakdown: entry.breakdown,
+            room: entry.room,
+            user: entry.user,
+            visibility: entry.visibility,
+            prev: Some(log.last_hash.clone()),
+            table: entry.table,
+            commitment: entry.commitment,
+            reveal: entry.reveal,
+            fair: entry.fair,
+        };
+
+        let mut line = serde_json::to_vec(&record)?;
+        let line_hash = hash(&line);
+        line.push(b'\n');
+        log.writer
+            .send(Message::Line(line))
+            .map_err(|_| writer_stopped())?;
+        log.next_id += 1;
+        log.last_hash = line_hash;
+
+        Ok(record)
+    }
+
+    /// Makes sure every record made so far is on disk.
+    pub fn sync(&self) -> Result<(), HistoryError> {
+        self.flush(true)
+    }
+
+    /// Waits for every record made so far to be written.
+    fn flush(&self, sync: bool) -> Result<(), HistoryError> {
+        let (done, flushed) = mpsc::channel();
+        self.log
+            .lock()
+            .unwrap()
+            .writer
+            .send(Message::Flush { sync, done })
+            .map_err(|_| writer_stopped())?;
+
+        flushed.recv().map_err(|_| writer_stopped())??;
+
+        Ok(())
+    }
+
+    /// Finds public records, newest first.
+    pub fn query(&self, query: &Query) -> Result<Page, HistoryError> {
+        self.flush(false)?;
+
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+
+        // Reveals come after the rolls they reveal, so they are found
+        // first.
+        let revealed = read(&self.path)?
+            .filter_map(|record| record.reveal.map(|reveal| reveal.id))
+            .collect::<HashSet<_>>();
+
+        // Keep one more than the page holds, to know if another follows.
+        let mut newest = VecDeque::with_capacity(limit + 1);
+        for record in read(&self.path)? {
+            if query.matches(&record, &revealed) {
+                if newest.len() > limit {
+                    newest.pop_front();
+                }
+                newest.push_back(record);
+            }
+        }
+
+        let more = newest.len() > limit;
+        if more {
+            newest.pop_front();
+        }
+
+        let records = newest.into_iter().rev().collect::<Vec<_>>();
+        let next = records.last().map(|record| record.id).filter(|_| more);
+
+        Ok(Page { records, next })
+    }
+
+    /// Walks the hash chain, stopping at the first broken link.
+    pub fn verify(&self) -> Result<Verification, HistoryError> {
+        self.flush(false)?;
+
+        verify(&self.path)
+    }
+}
+
+impl Drop for History {
+    /// Lets the records made so far be written, so the history can be
+    /// opened again straight away.
+    fn drop(&mut self) {
+        if let Err(e) = self.flush(false) {
+            log::error!("Could not write the history: {}", e);
+        }
+    }
+}
+
+/// Where the history's lines are written.
+trait Sink: Write {
+    /// Cuts off everything after the first `len` bytes.
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl Sink for File {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// Writes the lines sent to it, `len` bytes into the file, until the
+/// history is dropped or a write fails. A failed write is cut back off
+/// and stops the writer, so that every later record fails rather than
+/// chaining to a line that isn't there.
+fn write_lines(mut sink: impl Sink, mut len: u64, lines: mpsc::Receiver<Message>) {
+    for message in lines {
+        match message {
+            // One write per line, so that a crash leaves at worst a
+            // partial last line, which reading skips.
+            Message::Line(line) => {
+                if let Err(e) = sink.write_all(&line) {
+                    log::error!(
+                        "Could not write to the history, no more rolls will be recorded: {}",
+                        e
+                    );
+                    if let Err(e) = sink.truncate(len) {
+                        log::error!("Could not remove a partial line from the history: {}", e);
+                    }
+                    return;
+                }
+                len += line.len() as u64;
+            }
+            Message::Flush { sync, done } => {
+                let synced = if sync { sink.sync() } else { Ok(()) };
+                let _ = done.send(synced);
+            }
+        }
+    }
+}
+
+fn writer_stopped() -> HistoryError {
+    io::Error::new(io::ErrorKind::Other, "The history's writer has stopped.").into()
+}
+
+/// Walks the hash chain of the history in a data directory, without
+/// opening it for writing.
+pub fn verify_dir(dir: &Path) -> Result<Verification, HistoryError> {
+    verify(&dir.join(HISTORY_FILE))
+}
+
+fn verify(path: &Path) -> Result<Verification, HistoryError> {
+    let mut verification = Verification {
+        valid: true,
+        records: 0,
+        skipped: 0,
+        unchained: 0,
+        head: GENESIS.to_string(),
+        broken: None,
+    };
+
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let record = match line {
+            Ok(line) => serde_json::from_str::<Record>(&line).map(|record| (line, record)),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                verification.skipped += 1;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let (line, record) = match record {
+            Ok(record) => record,
+            Err(_) => {
+                verification.skipped += 1;
+                continue;
+            }
+        };
+
+        match &record.prev {
+            None => {
+                verification.valid = false;
+                verification.unchained += 1;
+            }
+            Some(prev) if prev != &verification.head => {
+                verification.valid = false;
+                verification.broken = Some(BrokenLink {
+                    line: number + 1,
+                    id: record.id,
+                    expected: verification.head.clone(),
+                    found: record.prev,
+                });
+                break;
+            }
+            Some(_) => {}
+        }
+
+        verification.head = hash(&line);
+        verification.records += 1;
+    }
+
+    Ok(verification)
+}
+
+/// Reads the records in a history file, oldest first.
+fn read(path: &Path) -> Result<impl Iterator<Item = Record>, HistoryError> {
+    Ok(lines(path)?.map(|(_, record)| record))
+}
+
+/// Reads the lines of a history file along with their records, skipping
+/// lines that can't be read.
+fn lines(path: &Path) -> Result<impl Iterator<Item = (String, Record)>, HistoryError> {
+    let lines = BufReader::new(File::open(path)?).lines();
+
+    Ok(lines.filter_map(|line| {
+        let record = line.map_err(HistoryError::from).and_then(|line| {
+            let record = serde_json::from_str(&line)?;
+            Ok((line, record))
+        });
+
+        match record {
+            Ok(record) => Some(record),
+            Err(e) => {
+                log::warn!("Skipping unreadable history line: {}", e);
+                None
+            }
+        }
+    }))
+}
+
+fn hash(line: impl AsRef<[u8]>) -> String {
+    hex::encode(Sha256::digest(line.as_ref()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("Could not access history: {0}")]
+    Io(#[from] io::Error),
+    #[error("Could not encode history: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    fn entry(roll: &str, room: Option<&str>, user: Option<&str>) -> Entry {
+        Entry {
+            roll: roll.to_string(),
+            totals: vec![4],
+            room: room.map(str::to_string),
+            user: user.map(str::to_string),
+            ..Entry::default()
+        }
+    }
+
+    #[test]
+    fn entry_roll_keeps_small_breakdowns() {
+        let small = Entry::roll("2x 3d6", 2, &StdDice::new(3, 1..=6, 1, 0, 0));
+        assert_eq!(2, small.totals.len());
+        assert_eq!(3, small.breakdown.unwrap()[0].faces.len());
+
+        let large = Entry::roll("2000d6", 1, &StdDice::new(2000, 1..=6, 1, 0, 0));
+        assert_eq!(1, large.totals.len());
+        assert!(large.breakdown.is_none());
+    }
+
+    #[test]
+    fn history_keeps_table_rolls() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(dir.path()).unwrap();
+        let roll = TableRoll {
+            table: "weather".to_string(),
+            roll: 3,
+            result: "Rain".to_string(),
+            nested: None,
+        };
+
+        history.record(Entry::table(&roll)).unwrap();
+
+        let page = history
+            .query(&Query {
+                roll: Some("table:weather".to_string()),
+                ..Query::default()
+            })
+            .unwrap();
+        assert_eq!(vec![3], page.records[0].totals);
+        assert_eq!(Some(roll), page.records[0].table);
+    }
+
+    #[test]
+    fn history_record_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let history = History::open(dir.path()).unwrap();
+        assert_eq!(1, history.record(entry("1d4", None, None)).unwrap().id);
+        assert_eq!(2, history.record(entry("1d4", None, None)).unwrap().id);
+        drop(history);
+
+        let history = History::open(dir.path()).unwrap();
+        assert_eq!(3, history.record(entry("1d4", None, None)).unwrap().id);
+    }
+
+    #[test]
+    fn history_query_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(dir.path()).unwrap();
+
+        history
+            .record(entry("1d4", Some("a"), Some("Ann")))
+            .unwrap();
+        history.record(entry("1d6", Some("a"), Some("Bo"))).unwrap();
+        history
+            .record(entry("1d6", Some("b"), Some("Ann")))
+            .unwrap();
+        history
+            .record(Entry {
+                visibility: Visibility::Blind,
+                ..entry("1d6", Some("a"), Some("Ann"))
+            })
+            .unwrap();
+
+        let ids = |query: Query| {
+            history
+                .query(&query)
+                .unwrap()
+                .records
+                .iter()
+                .map(|record| record.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec![3, 2, 1], ids(Query::default()));
+        assert_eq!(
+            vec![2, 1],
+            ids(Query {
+                room: Some("a".to_string()),
+                ..Query::default()
+            })
+        );
+        assert_eq!(
+            vec![3],
+            ids(Query {
+                user: Some("Ann".to_string()),
+                roll: Some("1d6".to_string()),
+                ..Query::default()
+            })
+        );
+        assert!(ids(Query {
+            from: Some(now() + 60_000),
+            ..Query::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn history_query_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(dir.path()).unwrap();
+
+        for _ in 0..5 {
+            history.record(entry("1d4", None, None)).unwrap();
+        }
+
+        let first = history
+            .query(&Query {
+                limit: Some(2),
+                ..Query::default()
+            })
+            .unwrap();
+        assert_eq!(Some(4), first.next);
+
+        let last = history
+            .query(&Query {
+                limit: Some(2),
+                before: Some(2),
+                ..Query::default()
+            })
+            .unwrap();
+        assert_eq!(1, last.records.len());
+        assert_eq!(None, last.next);
+    }
+
+    #[test]
+    fn history_skips_partial_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(dir.path()).unwrap();
+
+        history.record(entry("1d4", None, None)).unwrap();
+        history.sync().unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(HISTORY_FILE))
+            .unwrap()
+            .write_all(b"{\"id\": 2, \"times")
+            .unwrap();
+
+        assert_eq!(1, history.query(&Query::default()).unwrap().records.len());
+
+        drop(history);
+        let history = History::open(dir.path()).unwrap();
+        history.record(entry("1d4", None, None)).unwrap();
+
+        assert_eq!(2, history.query(&Query::default()).unwrap().records.len());
+
+        let verification = history.verify().unwrap();
+        assert!(verification.valid);
+        assert_eq!((2, 1), (verification.records, verification.skipped));
+    }
+
+    /// What a `Failing` sink has written, and how many bytes it has room
+    /// for before writes fail.
+    type Disk = Arc<Mutex<(Vec<u8>, usize)>>;
+
+    struct Failing(Disk);
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut disk = self.0.lock().unwrap();
+            let n = buf.len().min(disk.1.saturating_sub(disk.0.len()));
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "disk full"));
+            }
+            disk.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Sink for Failing {
+        fn truncate(&mut self, len: u64) -> io::Result<()> {
+            self.0.lock().unwrap().0.truncate(len as usize);
+            Ok(())
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn history_stops_when_a_write_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let disk = Disk::new(Mutex::new((Vec::new(), usize::MAX)));
+        let (writer, lines) = mpsc::channel();
+        let sink = Failing(disk.clone());
+        thread::spawn(move || write_lines(sink, 0, lines));
+
+        let history = History {
+            path: dir.path().join(HISTORY_FILE),
+            log: Mutex::new(Log {
+                writer,
+                next_id: 1,
+                last_hash: GENESIS.to_string(),
+            }),
+        };
+
+        history.record(entry("1d4", None, None)).unwrap();
+        history.sync().unwrap();
+        let written = {
+            let mut disk = disk.lock().unwrap();
+            disk.1 = disk.0.len() + 10;
+            disk.0.clone()
+        };
+
+        // The write fails after the roll is accepted, but nothing after it
+        // is, and the partial line is gone.
+        history.record(entry("1d6", None, None)).unwrap();
+        assert!(history.sync().is_err());
+        assert!(history.record(entry("1d8", None, None)).is_err());
+        assert_eq!(written, disk.lock().unwrap().0);
+    }
+
+    #[test]
+    fn history_verify_finds_broken_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HISTORY_FILE);
+        let history = History::open(dir.path()).unwrap();
+
+        for roll in &["1d4", "1d6", "1d8", "1d10"] {
+            history.record(entry(roll, None, None)).unwrap();
+        }
+
+        let verification = history.verify().unwrap();
+        assert!(verification.valid);
+        assert_eq!(4, verification.records);
+
+        let log = fs::read_to_string(&path).unwrap();
+        let broken = |log: String| {
+            fs::write(&path, log).unwrap();
+            let verification = verify_dir(dir.path()).unwrap();
+            assert!(!verification.valid);
+            let link = verification.broken.unwrap();
+            (link.line, link.id)
+        };
+
+        assert_eq!((3, 3), broken(log.replacen("1d6", "1d20", 1)));
+
+        let mut lines = log.lines().collect::<Vec<_>>();
+        lines.remove(1);
+        assert_eq!((2, 3), broken(lines.join("\n") + "\n"));
+    }
+
+    #[test]
+    fn history_verify_reports_unchained_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = serde_json::json!({
+            "id": 1,
+            "timestamp": 0,
+            "roll": "1d4",
+            "totals": [2],
+            "breakdown": null,
+            "room": null,
+            "user": null,
+            "visibility": "public",
+        });
+        fs::write(dir.path().join(HISTORY_FILE), format!("{}\n", old)).unwrap();
+
+        let history = History::open(dir.path()).unwrap();
+        history.record(entry("1d4", None, None)).unwrap();
+
+        let verification = history.verify().unwrap();
+        assert!(!verification.valid);
+        assert!(verification.broken.is_none());
+        assert_eq!((2, 1), (verification.records, verification.unchained));
+
+        // A history with every link stripped out isn't intact either.
+        drop(history);
+        let path = dir.path().join(HISTORY_FILE);
+        let stripped = read(&path)
+            .unwrap()
+            .map(|record| Record {
+                prev: None,
+                ..record
+            })
+            .map(|record| serde_json::to_string(&record).unwrap() + "\n")
+            .collect::<String>();
+        fs::write(&path, stripped).unwrap();
+
+        let verification = verify_dir(dir.path()).unwrap();
+        assert!(!verification.valid);
+        assert_eq!((2, 2), (verification.records, verification.unchained));
+    }
+}
@@ -0,0 +1,397 @@
+use futures::{stream, Stream};
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn, Service};
+use hyper::{Body, Request};
+use serde_derive::Deserialize;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use warp::reply::Response;
+use warp::Filter;
+
+use std::convert::{Infallible, TryFrom};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// The first of the sockets systemd passes in, after stdin, stdout and
+/// stderr.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Where the server takes connections from.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Listen {
+    /// A TCP address, such as `127.0.0.1:3000`.
+    Tcp(SocketAddr),
+    /// A Unix domain socket, given as `unix:/run/dicast.sock`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A socket passed in by systemd through `LISTEN_FDS`, given as
+    /// `systemd`.
+    #[cfg(unix)]
+    Systemd,
+}
+
+impl FromStr for Listen {
+    type Err = ListenError;
+
+    fn from_str(s: &str) -> Result<Listen, ListenError> {
+        #[cfg(unix)]
+        {
+            if s == "systemd" {
+                return Ok(Listen::Systemd);
+            }
+            if let Some(path) = s.strip_prefix("unix:") {
+                if path.is_empty() {
+                    return Err(ListenError::Invalid(s.to_string()));
+                }
+                return Ok(Listen::Unix(path.into()));
+            }
+        }
+
+        s.parse()
+            .map(Listen::Tcp)
+            .map_err(|_| ListenError::Invalid(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for Listen {
+    type Error = ListenError;
+
+    fn try_from(s: String) -> Result<Listen, ListenError> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            Listen::Systemd => write!(f, "the socket passed in by systemd"),
+        }
+    }
+}
+
+impl Listen {
+    pub async fn bind(&self) -> Result<Listener, ListenError> {
+        let bound = match self {
+            Listen::Tcp(addr) => TcpListener::bind(addr).await.map(Listener::Tcp),
+            #[cfg(unix)]
+            Listen::Unix(path) => bind_unix(path),
+            #[cfg(unix)]
+            Listen::Systemd => inherited(),
+        };
+
+        bound.map_err(|e| ListenError::Bind(self.clone(), e))
+    }
+}
+
+/// A socket being listened on.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        /// The socket file, removed once the server stops, unless it was
+        /// passed in.
+        path: Option<PathBuf>,
+    },
+}
+
+/// Binds a Unix domain socket, replacing one left behind by a server that
+/// didn't stop cleanly, but not one that is still in use.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let stale = std::fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_type().is_socket())
+        .unwrap_or(false);
+    if stale {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(Listener::Unix {
+        listener: UnixListener::bind(path)?,
+        path: Some(path.to_path_buf()),
+    })
+}
+
+/// Takes the socket systemd passed in, which may be TCP or Unix.
+#[cfg(unix)]
+fn inherited() -> io::Result<Listener> {
+    let fds = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "LISTEN_FDS is not set"))?;
+
+    // The sockets are for this process, not anything it starts.
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    if fds > 1 {
+        log::warn!(
+            "Using the first of the {} sockets passed in by systemd.",
+            fds
+        );
+    }
+
+    // Safety: systemd passes the sockets open from SD_LISTEN_FDS_START on,
+    // and nothing else in the process takes them.
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    // Only IP sockets have an address a TCP listener can read.
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        return Ok(Listener::Tcp(TcpListener::from_std(tcp)?));
+    }
+
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+    unix.set_nonblocking(true)?;
+
+    Ok(Listener::Unix {
+        listener: UnixListener::from_std(unix)?,
+        path: None,
+    })
+}
+
+/// How many sockets systemd passed in, if they were passed to this
+/// process.
+#[cfg(unix)]
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, id: u32) -> Option<u32> {
+    if pid?.parse::<u32>().ok()? != id {
+        return None;
+    }
+
+    fds?.parse().ok().filter(|&fds| fds > 0)
+}
+
+/// The client's address, set on requests by `with_remote`.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// The client's address, if it connected over TCP, whether warp or
+/// `with_remote` saw it.
+pub fn remote() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<RemoteAddr>())
+        .map(|addr: Option<SocketAddr>, set: Option<RemoteAddr>| addr.or(set.map(|set| set.0)))
+}
+
+/// Sets the client's address on every request to a service, as warp only
+/// knows it when it binds the socket itself.
+pub fn with_remote<S>(
+    mut service: S,
+    remote: Option<SocketAddr>,
+) -> impl Service<Request<Body>, Response = Response, Error = Infallible, Future = S::Future>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible>,
+{
+    service_fn(move |mut req: Request<Body>| {
+        if let Some(remote) = remote {
+            req.extensions_mut().insert(RemoteAddr(remote));
+        }
+        service.call(req)
+    })
+}
+
+/// Serves HTTP until `signal` resolves, then finishes the requests it has,
+/// like warp's `bind_with_graceful_shutdown`.
+pub async fn serve<S>(
+    service: S,
+    listener: Listener,
+    signal: impl Future<Output = ()>,
+) -> Result<(), ListenError>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    match listener {
+        Listener::Tcp(listener) => {
+            let make_service = make_service_fn(move |stream: &TcpStream| {
+                let service = with_remote(service.clone(), stream.peer_addr().ok());
+                async move { Ok::<_, Infallible>(service) }
+            });
+
+            hyper::Server::builder(accept::from_stream(tcp_streams(listener)))
+                .serve(make_service)
+                .with_graceful_shutdown(signal)
+                .await?;
+        }
+        #[cfg(unix)]
+        Listener::Unix { listener, path } => {
+            let make_service = make_service_fn(move |_: &UnixStream| {
+                let service = service.clone();
+                async move { Ok::<_, Infallible>(service) }
+            });
+
+            let served = hyper::Server::builder(accept::from_stream(unix_streams(listener)))
+                .serve(make_service)
+                .with_graceful_shutdown(signal)
+                .await;
+
+            if let Some(path) = path {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    log::warn!("Could not remove {}: {}", path.display(), e);
+                }
+            }
+            served?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The connections made to a listener. Failing to accept one is logged
+/// rather than passed on, which would stop the server.
+fn tcp_streams(listener: TcpListener) -> impl Stream<Item = io::Result<TcpStream>> {
+    stream::unfold(listener, |mut listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(e) => log::warn!("Could not accept a connection: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(unix)]
+fn unix_streams(listener: UnixListener) -> impl Stream<Item = io::Result<UnixStream>> {
+    stream::unfold(listener, |mut listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((Ok(stream), listener)),
+                Err(e) => log::warn!("Could not accept a connection: {}", e),
+            }
+        }
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum ListenError {
+    #[error("Expected an address, unix:PATH or systemd, got {0:?}")]
+    Invalid(String),
+    #[error("Could not listen on {0}: {1}")]
+    Bind(Listen, #[source] io::Error),
+    #[error("HTTPS can only be served over TCP.")]
+    TlsOverUnix,
+    #[error("Server failed: {0}")]
+    Server(#[from] hyper::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn listen_parses() {
+        assert_eq!(
+            Listen::Tcp("127.0.0.1:3000".parse().unwrap()),
+            "127.0.0.1:3000".parse().unwrap()
+        );
+        assert_eq!(
+            Listen::Unix("/run/dicast.sock".into()),
+            "unix:/run/dicast.sock".parse().unwrap()
+        );
+        assert_eq!(Listen::Systemd, "systemd".parse().unwrap());
+        assert!("unix:".parse::<Listen>().is_err());
+        assert!("localhost".parse::<Listen>().is_err());
+    }
+
+    #[test]
+    fn listen_fds_are_for_this_process() {
+        assert_eq!(Some(2), listen_fds(Some("42"), Some("2"), 42));
+        assert_eq!(None, listen_fds(Some("41"), Some("2"), 42));
+        assert_eq!(None, listen_fds(Some("42"), Some("0"), 42));
+        assert_eq!(None, listen_fds(None, Some("1"), 42));
+        assert_eq!(None, listen_fds(Some("42"), None, 42));
+    }
+
+    fn remote_route() -> warp::filters::BoxedFilter<(String,)> {
+        remote()
+            .map(|remote: Option<SocketAddr>| match remote {
+                Some(addr) => addr.ip().to_string(),
+                None => "none".to_string(),
+            })
+            .boxed()
+    }
+
+    async fn get(mut stream: impl AsyncRead + AsyncWrite + Unpin) -> String {
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serve_sets_remote_over_tcp() {
+        let listener = Listen::Tcp("127.0.0.1:0".parse().unwrap())
+            .bind()
+            .await
+            .unwrap();
+        let addr = match &listener {
+            Listener::Tcp(listener) => listener.local_addr().unwrap(),
+            _ => unreachable!(),
+        };
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(warp::service(remote_route()), listener, async move {
+            drop(stopped.await)
+        }));
+
+        let response = get(TcpStream::connect(addr).await.unwrap()).await;
+        assert!(response.starts_with("HTTP/1.0 200"));
+        assert!(response.ends_with("127.0.0.1"));
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_over_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dicast.sock");
+        let listen = Listen::Unix(path.clone());
+
+        // Left behind by a server that was killed.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let listener = listen.bind().await.unwrap();
+        assert!(matches!(
+            listen.bind().await,
+            Err(ListenError::Bind(_, e)) if e.kind() == io::ErrorKind::AddrInUse
+        ));
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(warp::service(remote_route()), listener, async move {
+            drop(stopped.await)
+        }));
+
+        let response = get(UnixStream::connect(&path).await.unwrap()).await;
+        assert!(response.starts_with("HTTP/1.0 200"));
+        assert!(response.ends_with("none"));
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
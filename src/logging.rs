@@ -0,0 +1,165 @@
+use serde_derive::Deserialize;
+use thiserror::Error;
+use tracing::subscriber::SetGlobalDefaultError;
+use tracing::Subscriber;
+use tracing_log::AsLog;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use std::io;
+use std::str::FromStr;
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// For people, coloured when written to a terminal.
+    Text,
+    /// A JSON object per line, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = LogError;
+
+    fn from_str(s: &str) -> Result<LogFormat, LogError> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(LogError::InvalidFormat(s.to_string())),
+        }
+    }
+}
+
+/// A subscriber that writes events filtered by `filter`, each with the
+/// fields of the span it happened in, such as its request's ID.
+fn subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        // The request span is the only one, so the current span holds
+        // every field there is.
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+                .finish(),
+        ),
+    }
+}
+
+/// Logs through both `log` and `tracing`, filtered by `RUST_LOG`.
+pub fn init(format: LogFormat) -> Result<(), LogError> {
+    let filter = EnvFilter::from_default_env();
+    let max_level = filter.max_level_hint().map(|level| level.as_log());
+
+    let mut log_tracer = tracing_log::LogTracer::builder();
+    if let Some(max_level) = max_level {
+        log_tracer = log_tracer.with_max_level(max_level);
+    }
+    log_tracer.init()?;
+    tracing::subscriber::set_global_default(subscriber(format, filter, io::stderr))?;
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum LogError {
+    #[error("Expected a log format of text or json, got {0:?}")]
+    InvalidFormat(String),
+    #[error("Could not set the logger: {0}")]
+    Logger(#[from] log::SetLoggerError),
+    #[error("Could not set the tracing subscriber: {0}")]
+    Subscriber(#[from] SetGlobalDefaultError),
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use serde_json::Value;
+
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer that can be read back from once the logger has it.
+    #[derive(Clone, Default)]
+    pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Buffer {
+        pub fn lines(&self) -> Vec<Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A JSON logger of info and above, and what it has written.
+    pub fn json_logger() -> (Box<dyn Subscriber + Send + Sync>, Buffer) {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+
+        (
+            subscriber(LogFormat::Json, EnvFilter::new("info"), move || writer.clone()),
+            buffer,
+        )
+    }
+
+    #[test]
+    fn events_carry_span_fields() {
+        let (logger, buffer) = json_logger();
+
+        tracing::subscriber::with_default(logger, || {
+            let span = tracing::info_span!(
+                target: "dicast::access",
+                "request",
+                request_id = "abc",
+                expression = tracing::field::Empty,
+            );
+            let _entered = span.enter();
+            tracing::Span::current().record("expression", "3d6");
+
+            tracing::info!(total = 11, "Rolled.");
+            tracing::debug!("Filtered out.");
+        });
+        tracing::info!("Not seen by the logger.");
+
+        let lines = buffer.lines();
+        assert_eq!(1, lines.len());
+        assert_eq!("INFO", lines[0]["level"]);
+        assert_eq!("Rolled.", lines[0]["message"]);
+        assert_eq!("abc", lines[0]["span"]["request_id"]);
+        assert_eq!("3d6", lines[0]["span"]["expression"]);
+        assert_eq!(11, lines[0]["total"]);
+    }
+
+    #[test]
+    fn log_format_parses() {
+        assert_eq!(LogFormat::Json, "json".parse().unwrap());
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
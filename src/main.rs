@@ -1,28 +1,74 @@
+use futures::FutureExt;
 use serde_derive::{Serialize, Deserialize};
+use tokio::sync::oneshot;
+use warp::http::StatusCode;
 use warp::Filter;
 use warp::reply::Reply;
 
 use dice::dice::DiceRoller;
-use dice::parse::parse_str;
+use dice::sim::Analysis;
+use dice::tables::Tables;
 
+mod access;
+mod accounts;
+mod assets;
+mod compress;
+mod config;
+mod decks;
+mod error;
+mod fair;
+mod format;
+mod health;
+mod history;
+mod listen;
+mod logging;
+mod metrics;
 mod mime;
+mod proxy;
+mod ratelimit;
+mod rooms;
+mod sets;
+mod tls;
+
+use crate::access::AccessLog;
+use crate::accounts::Accounts;
+use crate::config::{Command, Config, Limits};
+use crate::decks::Decks;
+use crate::error::{blocking, ApiError, ErrorBody, ForWarp};
+use crate::format::Format;
+use crate::health::Health;
+use crate::history::{Entry, History, Query};
+use crate::listen::{ListenError, Listener};
+use crate::proxy::{Proxies, TrustedProxy};
+use crate::ratelimit::{RateLimiter, SystemClock};
+use crate::rooms::Rooms;
+use crate::sets::Sets;
+use crate::tls::TlsError;
 
 // use crate::template::{compile_templates, serve_template, State};
 
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::net::SocketAddr;
-// use std::sync::Arc;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const STATIC_DIR_PATH: &str = "./static/";
-const APP_JS: &str = "./frontend/static/main.js";
-const APP_WASM: &str = "./frontend/static/main_bg.wasm";
+const DEFAULT_TRIALS: u64 = 100_000;
+/// The longest shutting down waits for players to leave their rooms.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::load()?;
+
     if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", "info");
+        std::env::set_var("RUST_LOG", &config.log_level);
+    }
+    logging::init(config.log_format)?;
+
+    if let Some(Command::VerifyHistory) = config.command {
+        return verify_history(&config.data_dir);
     }
-    pretty_env_logger::init();
 
     // let templates = compile_templates(&[
     //     "./templates/index.html.liquid",
@@ -39,50 +85,451 @@ async fn main() -> Result<(), Box<dyn Error>> {
     //     move || filter.clone()
     // };
 
-    let statics = warp::filters::method::get()
-        .and(warp::fs::dir(STATIC_DIR_PATH))
-        .and(warp::path::end());
+    let statics = assets::routes(&config);
 
-    let js = warp::filters::method::get()
-        .and(warp::path("main.js"))
+    let limits = config.limits;
+    let with_limits = warp::any().map(move || limits);
+
+    let history = Arc::new(History::open(&config.data_dir)?);
+    let with_history = {
+        let history = history.clone();
+        warp::any().map(move || history.clone())
+    };
+
+    let dice = warp::filters::method::post()
+        .and(warp::path("dice"))
         .and(warp::path::end())
-        .and(warp::fs::file(APP_JS));
+        .and(warp::body::content_length_limit(config.body_limit))
+        .and(warp::body::json())
+        .and(with_limits)
+        .and(with_history.clone())
+        .and_then(|req: DiceRequest, limits: Limits, history: Arc<History>| async move {
+            roll_dice(req, &limits, &history).for_warp()
+        });
 
-    let wasm = warp::filters::method::get()
-        .and(warp::path("main_bg.wasm"))
+    let batch = warp::filters::method::post()
+        .and(warp::path("dice"))
+        .and(warp::path("batch"))
         .and(warp::path::end())
-        .and(warp::fs::file(APP_WASM));
+        .and(warp::body::content_length_limit(config.body_limit))
+        .and(warp::body::json())
+        .and(with_limits)
+        .and(with_history.clone())
+        .and_then(|req: Vec<BatchItem>, limits: Limits, history: Arc<History>| async move {
+            roll_batch(req, &limits, &history).for_warp()
+        });
 
-    let dice = warp::filters::method::post()
+    let roll_path = warp::filters::method::get()
+        .and(warp::path!("roll" / String))
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_limits)
+        .and(with_history.clone())
+        .and_then(|expr: String, accept: Option<String>, limits: Limits, history: Arc<History>| async move {
+            let expr = percent_encoding::percent_decode_str(&expr)
+                .decode_utf8()
+                .map(|expr| expr.into_owned())
+                .map_err(|_| {
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_path",
+                        "The roll is not valid UTF-8.",
+                    )
+                });
+
+            expr.and_then(|expr| roll_get(expr, accept, &limits, &history)).for_warp()
+        });
+
+    let roll_query = warp::filters::method::get()
+        .and(warp::path("roll"))
+        .and(warp::path::end())
+        .and(warp::query::<RollQuery>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_limits)
+        .and(with_history.clone())
+        .and_then(|query: RollQuery, accept: Option<String>, limits: Limits, history: Arc<History>| async move {
+            roll_get(query.e, accept, &limits, &history).for_warp()
+        });
+
+    // Every simulation shares one pool, so that however many come in at
+    // once, they never use more than the configured threads.
+    let simulations = Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(config.simulation_threads)
+            .thread_name(|i| format!("simulation-{}", i))
+            .build()?,
+    );
+    let stats = warp::filters::method::post()
         .and(warp::path("dice"))
-        // 16kb
-        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(config.body_limit))
+        .and(warp::body::json())
+        .and(with_limits)
+        .and_then(move |req: StatsRequest, limits: Limits| {
+            let simulations = simulations.clone();
+            async move { roll_stats(req, &limits, &simulations).await.for_warp() }
+        });
+
+    let tables = Arc::new(if config.tables_dir.is_dir() {
+        Tables::load_dir(&config.tables_dir)?
+    } else {
+        Tables::new()
+    });
+    log::info!("{} tables loaded.", tables.len());
+
+    let with_tables = warp::any().map(move || tables.clone());
+
+    let roll_table = warp::filters::method::post()
+        .and(warp::path!("tables" / String / "roll"))
+        .and(with_tables)
+        .and(with_history.clone())
+        .and_then(|name: String, tables: Arc<Tables>, history: Arc<History>| async move {
+            roll_table(name, &tables, &history).for_warp()
+        });
+
+    // Decks are kept per session and roll, so that each session draws
+    // from its own deck for every expression it rolls.
+    let decks = Arc::new(Decks::new(config.deck_idle));
+    tokio::spawn(decks::expire_idle(decks.clone()));
+    let with_decks = warp::any().map(move || decks.clone());
+
+    let deck = warp::filters::method::post()
+        .and(warp::path("deck"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(config.body_limit))
         .and(warp::body::json())
-        .map(|req: DiceRequest| {
-            log::info!("Received a request: {:?}", req.roll);
+        .and(with_decks)
+        .and(with_limits)
+        .and(with_history.clone())
+        .and_then(|req: DeckRequest, decks: Arc<Decks>, limits: Limits, history: Arc<History>| async move {
+            blocking(move || deal_deck(req, &decks, &limits, &history))
+                .await
+                .for_warp()
+        });
+
+    let history_route = warp::filters::method::get()
+        .and(warp::path("history"))
+        .and(warp::path::end())
+        .and(warp::query::<Query>())
+        .and(with_history.clone())
+        .and_then(|query: Query, history: Arc<History>| async move {
+            query_history(query, history).await.for_warp()
+        });
+
+    let history_verify = warp::filters::method::get()
+        .and(warp::path!("history" / "verify"))
+        .and(with_history)
+        .and_then(|history: Arc<History>| async move {
+            verify_history_chain(history).await.for_warp()
+        });
 
-            let mut roller = DiceRoller::new();
+    let rooms = Arc::new(Rooms::new(config.room_idle, config.room_rate_limit));
+    tokio::spawn(rooms::expire_idle(rooms.clone()));
 
-            if let Ok((times, dice)) = parse_str(req.roll.as_str()) {
-                let roll = roller.roll_dice_times(&dice, times);
+    let fair = fair::routes(
+        Arc::new(fair::Seeds::new()),
+        history.clone(),
+        config.limits,
+        config.body_limit,
+    );
 
-                warp::reply::json(&DiceResponse { roll }).into_response()
-            } else {
-                http::Response::builder()
-                    .status(422)
-                    .body("Invalid roll.").into_response()
+    let metrics = metrics::routes(rooms.clone());
+    let room_routes = rooms::routes(rooms.clone(), history.clone(), config.limits);
+
+    let health = Arc::new(Health::new());
+    let health_routes = health::routes(health.clone());
+
+    let accounts = Arc::new(Accounts::open(&config.data_dir)?);
+    let sets = sets::routes(
+        Arc::new(Sets::open(&config.data_dir)?),
+        accounts.clone(),
+        config.limits,
+        config.body_limit,
+    );
+    let accounts = accounts::routes(accounts, config.body_limit, config.tls.is_some());
+
+    // Static files, metrics and health checks are left out of the limit,
+    // so loading the page or polling can't use up a client's requests.
+    let rate_limit = ratelimit::by_ip(Arc::new(RateLimiter::new(
+        config.rate_limit,
+        Arc::new(SystemClock),
+    )));
+
+    let api = stats
+        .or(batch)
+        .or(dice)
+        .or(roll_path)
+        .or(roll_query)
+        .or(roll_table)
+        .or(deck)
+        .or(room_routes)
+        .or(history_route)
+        .or(history_verify)
+        .or(fair)
+        .or(accounts)
+        .or(sets);
+    let routes = statics
+        .or(metrics)
+        .or(health_routes)
+        .or(rate_limit.and(api))
+        .recover(error::handle_rejection)
+        .with(warp::log::custom(metrics::record));
+
+    let proxies = Proxies::new(config.trusted_proxies.clone());
+    let service = AccessLog::new(warp::service(routes), proxies);
+    let listener = config.listen.bind().await?;
+    log::info!("Serving server on {}", config.listen);
+    #[cfg(unix)]
+    {
+        if matches!(listener, Listener::Unix { .. })
+            && !config.trusted_proxies.contains(&TrustedProxy::Unix)
+        {
+            log::warn!(
+                "Requests over the Unix socket share one rate limit, as their clients are \
+                 unknown. Trust the proxy with --trusted-proxy unix to limit each client."
+            );
+        }
+    }
+    let shutdown = shut_down(health, rooms).shared();
+
+    match (&config.tls, listener) {
+        (Some(tls), Listener::Tcp(listener)) => {
+            let certs = Arc::new(tls::Certs::load(&tls.cert, &tls.key)?);
+            tokio::spawn(tls::reload_on_hangup(certs.clone()));
+
+            if let Some(addr) = tls.redirect_addr {
+                log::info!("Redirecting HTTP on {} to HTTPS", addr);
+                let https_port = listener.local_addr()?.port();
+                let redirect = tls::redirect(https_port).recover(error::handle_rejection);
+                let (_, server) = warp::serve(redirect)
+                    .try_bind_with_graceful_shutdown(addr, shutdown.clone())
+                    .map_err(|e| TlsError::Redirect(addr, e))?;
+                tokio::spawn(server);
             }
-    });
 
-    let addr = "0.0.0.0:3000";
-    log::info!("Serving server on {}", addr);
-    warp::serve(statics.or(js).or(wasm).or(dice))
-        .run(addr.parse::<SocketAddr>()?)
-        .await;
+            tls::serve(service, listener, certs, shutdown).await?;
+        }
+        #[cfg(unix)]
+        (Some(_), Listener::Unix { .. }) => return Err(ListenError::TlsOverUnix.into()),
+        (None, listener) => listen::serve(service, listener, shutdown).await?,
+    }
+
+    // Rolls are written as they are made, but may not have reached the
+    // disk yet.
+    history.sync()?;
+    log::info!("Shut down.");
 
     Ok(())
 }
 
+/// Resolves once a signal to stop arrives and the rooms have emptied,
+/// after which the server finishes the requests it has and stops.
+async fn shut_down(health: Arc<Health>, rooms: Arc<Rooms>) {
+    health::signal().await;
+    log::info!("Shutting down...");
+    health.shut_down();
+
+    if !rooms.close(DRAIN_TIMEOUT).await {
+        log::warn!("Some players were still connected after {:?}.", DRAIN_TIMEOUT);
+    }
+}
+
+fn roll_dice(req: DiceRequest, limits: &Limits, history: &History) -> Result<impl Reply, ApiError> {
+    log::info!("Received a request: {:?}", req.roll);
+
+    let roll = roll(&req.roll, limits, history)?;
+
+    Ok(warp::reply::json(&DiceResponse { roll }))
+}
+
+/// Rolls for clients that can't send a body, answering in plain text, JSON
+/// or Markdown depending on what they accept.
+fn roll_get(
+    expr: String,
+    accept: Option<String>,
+    limits: &Limits,
+    history: &History,
+) -> Result<impl Reply, ApiError> {
+    log::info!("Received a GET request: {:?}", expr);
+
+    Format::negotiate(accept.as_deref()).render(&expr, roll(&expr, limits, history))
+}
+
+/// Rolls each item in turn. An item that fails carries its own error
+/// rather than failing the batch.
+fn roll_batch(
+    req: Vec<BatchItem>,
+    limits: &Limits,
+    history: &History,
+) -> Result<impl Reply, ApiError> {
+    log::info!("Received a batch request of {} rolls", req.len());
+
+    limits.check_batch(req.len())?;
+
+    // The batch as a whole may only roll as many dice as a single roll.
+    let mut rolled = 0;
+    let results = req
+        .into_iter()
+        .map(|item| match roll_counting(&item.roll, limits, history, &mut rolled) {
+            Ok(roll) => BatchResult {
+                name: item.name,
+                roll: Some(roll),
+                error: None,
+            },
+            Err(e) => BatchResult {
+                name: item.name,
+                roll: None,
+                error: Some(e.body),
+            },
+        })
+        .collect::<Vec<_>>();
+
+    Ok(warp::reply::json(&results))
+}
+
+/// Rolls and records a roll, returning its totals.
+fn roll(roll: &str, limits: &Limits, history: &History) -> Result<Vec<i64>, ApiError> {
+    roll_counting(roll, limits, history, &mut 0)
+}
+
+/// Rolls and records a roll like `roll`, adding the dice it rolls to
+/// `rolled` unless that would take it past the limit.
+fn roll_counting(
+    roll: &str,
+    limits: &Limits,
+    history: &History,
+    rolled: &mut i64,
+) -> Result<Vec<i64>, ApiError> {
+    let (times, dice) = metrics::parse(roll)?;
+    let total = rolled.saturating_add(limits.check(times, &dice)?);
+    limits.check_batch_dice(total)?;
+    *rolled = total;
+
+    let record = history.record(Entry::roll(roll, times, &dice))?;
+
+    Ok(record.totals)
+}
+
+/// Queries the history on a blocking thread, as it reads the whole file.
+async fn query_history(query: Query, history: Arc<History>) -> Result<impl Reply, ApiError> {
+    let page = blocking(move || Ok(history.query(&query)?)).await?;
+
+    Ok(warp::reply::json(&page))
+}
+
+/// Walks the history's hash chain on a blocking thread, like
+/// `query_history`.
+async fn verify_history_chain(history: Arc<History>) -> Result<impl Reply, ApiError> {
+    let verification = blocking(move || Ok(history.verify()?)).await?;
+
+    Ok(warp::reply::json(&verification))
+}
+
+/// Checks the history's hash chain for `dicast verify-history`, exiting
+/// with an error at the first broken link or if any record is unchained.
+fn verify_history(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let verification = history::verify_dir(data_dir)?;
+
+    println!(
+        "Checked {} records, skipping {} unreadable lines.",
+        verification.records, verification.skipped
+    );
+
+    match verification.broken {
+        None if verification.unchained > 0 => {
+            eprintln!(
+                "{} records have no link to the records before them.",
+                verification.unchained
+            );
+            std::process::exit(1);
+        }
+        None => {
+            println!("The chain is intact. Its head is {}.", verification.head);
+            Ok(())
+        }
+        Some(link) => {
+            eprintln!(
+                "Broken link at line {}, record {}: expected {}, found {}.",
+                link.line,
+                link.id,
+                link.expected,
+                link.found.as_deref().unwrap_or("no hash")
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Analyzes a roll on the simulation pool, as a simulation can take a
+/// while. Nothing is rolled for anyone to see, so nothing is recorded.
+async fn roll_stats(
+    req: StatsRequest,
+    limits: &Limits,
+    simulations: &rayon::ThreadPool,
+) -> Result<impl Reply, ApiError> {
+    log::info!("Received a stats request: {:?}", req.roll);
+
+    let (_, dice) = metrics::parse(req.roll.as_str())?;
+    limits.check(1, &dice)?;
+    let trials = limits.trials(req.trials.unwrap_or(DEFAULT_TRIALS), &dice);
+    let shares = simulations.current_num_threads();
+    let (send, analysis) = oneshot::channel();
+    simulations.spawn(move || {
+        let _ = send.send(DiceRoller::new().analyze(&dice, trials, shares));
+    });
+    let analysis = analysis.await.map_err(|_| {
+        log::error!("Stats simulation failed.");
+        ApiError::internal()
+    })?;
+
+    Ok(warp::reply::json(&StatsResponse::from(analysis)))
+}
+
+fn roll_table(name: String, tables: &Tables, history: &History) -> Result<impl Reply, ApiError> {
+    log::info!("Received a table request: {:?}", name);
+
+    let mut roller = DiceRoller::new();
+    let roll = tables.roll(&name, &mut roller)?;
+    history.record(Entry::table(&roll))?;
+
+    Ok(warp::reply::json(&roll))
+}
+
+/// Deals from a deck, which may first have to be built. Building one can
+/// take a while, so this is run on a blocking thread.
+fn deal_deck(
+    req: DeckRequest,
+    decks: &Decks,
+    limits: &Limits,
+    history: &History,
+) -> Result<impl Reply, ApiError> {
+    log::info!("Received a deck request: {:?}", req.roll);
+
+    let (times, dice) = metrics::parse(req.roll.as_str())?;
+    limits.check(times, &dice)?;
+
+    let deal = decks.deal(
+        req.session.as_deref(),
+        &req.roll,
+        &dice,
+        times,
+        Instant::now(),
+    )?;
+
+    let record = history.record(Entry {
+        roll: req.roll,
+        totals: deal.roll,
+        ..Entry::default()
+    })?;
+
+    Ok(warp::reply::json(&DeckResponse {
+        roll: record.totals,
+        session: deal.session,
+        remaining: deal.remaining,
+        size: deal.size,
+    }))
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct DiceRequest {
     pub roll: String,
@@ -93,29 +540,91 @@ struct DiceResponse {
     pub roll: Vec<i64>,
 }
 
-trait ForWarp {
-    type Reply;
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct RollQuery {
+    /// The roll expression.
+    pub e: String,
+}
+
+/// One roll of a batch, shaped like the frontend's `DieData`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct BatchItem {
+    pub name: String,
+    pub roll: String,
+}
+
+/// The outcome of one roll of a batch, holding either the roll or the
+/// error it failed with.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct BatchResult {
+    pub name: String,
+    pub roll: Option<Vec<i64>>,
+    pub error: Option<ErrorBody>,
+}
 
-    fn for_warp(self) -> Result<Self::Reply, warp::Rejection>;
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct DeckRequest {
+    pub roll: String,
+    /// The session to deal from, as issued by the server. Left out, a new
+    /// session is started.
+    pub session: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct DeckResponse {
+    pub roll: Vec<i64>,
+    /// The session dealt from, to send with later deals.
+    pub session: String,
+    /// Cards left before the deck is reshuffled.
+    pub remaining: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct StatsRequest {
+    pub roll: String,
+    pub trials: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct StatsResponse {
+    /// Either "exact" or "simulated".
+    pub method: String,
+    pub trials: Option<u64>,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub confidence_interval: Option<(f64, f64)>,
+    /// The probability of each result, estimated if simulated.
+    pub distribution: BTreeMap<i64, f64>,
 }
 
-impl<T> ForWarp for Result<T, Box<dyn Error>> 
-where
-    T: warp::Reply + 'static
-{
-    type Reply = Box<dyn warp::Reply>;
+impl From<Analysis> for StatsResponse {
+    fn from(analysis: Analysis) -> Self {
+        match analysis {
+            Analysis::Exact(exact) => StatsResponse {
+                method: "exact".to_string(),
+                trials: None,
+                mean: exact.mean,
+                std_dev: exact.std_dev,
+                confidence_interval: None,
+                distribution: exact.probabilities,
+            },
+            Analysis::Simulated(simulation) => {
+                let trials = simulation.trials as f64;
 
-    fn for_warp(self) -> Result<Self::Reply, warp::Rejection> {
-        let b: Box<dyn warp::Reply> = match self {
-            Ok(reply) => Box::new(reply),
-            Err(e) => {
-                log::error!("Error: {}", e);
-                let res = http::Response::builder()
-                    .status(500)
-                    .body("Something went wrong, apologies.");
-                Box::new(res)
+                StatsResponse {
+                    method: "simulated".to_string(),
+                    trials: Some(simulation.trials),
+                    mean: simulation.mean,
+                    std_dev: simulation.std_dev,
+                    confidence_interval: Some(simulation.confidence_interval),
+                    distribution: simulation
+                        .histogram
+                        .into_iter()
+                        .map(|(value, count)| (value, count as f64 / trials))
+                        .collect(),
+                }
             }
-        };
-        Ok(b)
+        }
     }
 }
@@ -0,0 +1,254 @@
+use lazy_static::lazy_static;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use warp::filters::BoxedFilter;
+use warp::http::header::CONTENT_TYPE;
+use warp::log::Info;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use dice::dice::StdDice;
+use dice::parse::{parse_str, ParseError};
+
+use crate::rooms::Rooms;
+
+use std::sync::Arc;
+
+const DICE_COUNT_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 4.0, 5.0, 10.0, 20.0, 100.0, 1e3, 1e4, 1e6];
+const DICE_SIDES_BUCKETS: &[f64] = &[2.0, 4.0, 6.0, 8.0, 10.0, 12.0, 20.0, 100.0, 1e3, 1e6, 1e9];
+
+lazy_static! {
+    static ref METRICS: Metrics = Metrics::new();
+}
+
+/// Everything `/metrics` reports. The metrics are kept in one place, like
+/// a logger, so that code anywhere can count without being handed them.
+struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    parse_errors: IntCounterVec,
+    dice_count: Histogram,
+    dice_sides: Histogram,
+    rooms: IntGauge,
+    connections: IntGauge,
+    asset_hits: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("dicast".to_string()), None).unwrap(),
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests answered."),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time taken to answer HTTP requests.",
+                ),
+                &["method", "route"],
+            )
+            .unwrap(),
+            parse_errors: IntCounterVec::new(
+                Opts::new("parse_errors_total", "Rolls that failed to parse."),
+                &["kind"],
+            )
+            .unwrap(),
+            dice_count: Histogram::with_opts(
+                HistogramOpts::new("dice_count", "Dice in each parsed roll.")
+                    .buckets(DICE_COUNT_BUCKETS.to_vec()),
+            )
+            .unwrap(),
+            dice_sides: Histogram::with_opts(
+                HistogramOpts::new("dice_sides", "Sides of the dice in each parsed roll.")
+                    .buckets(DICE_SIDES_BUCKETS.to_vec()),
+            )
+            .unwrap(),
+            rooms: IntGauge::new("rooms", "Rooms open.").unwrap(),
+            connections: IntGauge::new("websocket_connections", "Players connected to rooms.")
+                .unwrap(),
+            asset_hits: IntCounterVec::new(
+                Opts::new("asset_hits_total", "Static assets served."),
+                &["asset"],
+            )
+            .unwrap(),
+        };
+
+        let collectors: Vec<Box<dyn Collector>> = vec![
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.latency.clone()),
+            Box::new(metrics.parse_errors.clone()),
+            Box::new(metrics.dice_count.clone()),
+            Box::new(metrics.dice_sides.clone()),
+            Box::new(metrics.rooms.clone()),
+            Box::new(metrics.connections.clone()),
+            Box::new(metrics.asset_hits.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+
+        metrics
+    }
+}
+
+/// Parses a roll, counting failures by kind and the size of what parsed.
+/// The roll is noted on the request's span, for the access log.
+pub fn parse(roll: &str) -> Result<(i64, StdDice), ParseError> {
+    tracing::Span::current().record("expression", roll);
+    let result = parse_str(roll);
+
+    match &result {
+        Ok((_, dice)) => {
+            let sides = dice.range().end() - dice.range().start() + 1;
+
+            METRICS.dice_count.observe(dice.count() as f64);
+            METRICS.dice_sides.observe(sides as f64);
+        }
+        Err(e) => {
+            let kind = match e {
+                ParseError::InvalidToken(..) => "invalid_token",
+                ParseError::UnexpectedToken(..) => "unexpected_token",
+                ParseError::NumberTooLarge(..) => "number_too_large",
+            };
+
+            METRICS.parse_errors.with_label_values(&[kind]).inc();
+        }
+    }
+
+    result
+}
+
+pub fn asset_hit(name: &str) {
+    METRICS.asset_hits.with_label_values(&[name]).inc();
+}
+
+/// Counts a room's player as connected for as long as it is held.
+pub struct Connection(());
+
+impl Connection {
+    pub fn open() -> Connection {
+        METRICS.connections.inc();
+        Connection(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        METRICS.connections.dec();
+    }
+}
+
+/// Counts and times a request, for use with `warp::log::custom`.
+pub fn record(info: Info) {
+    let method = info.method().as_str();
+    let route = route(info.path());
+
+    METRICS
+        .requests
+        .with_label_values(&[method, route, info.status().as_str()])
+        .inc();
+    METRICS
+        .latency
+        .with_label_values(&[method, route])
+        .observe(info.elapsed().as_secs_f64());
+}
+
+/// The route a path was served by, with ids and rolls left out so that
+/// there are only so many to count.
+fn route(path: &str) -> &'static str {
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+    match segments.as_slice() {
+        ["dice"] => "/dice",
+        ["dice", "batch"] => "/dice/batch",
+        ["dice", "stats"] => "/dice/stats",
+        ["roll"] => "/roll",
+        ["roll", _] => "/roll/{roll}",
+        ["tables", _, "roll"] => "/tables/{name}/roll",
+        ["deck"] => "/deck",
+        ["rooms", _, "ws"] => "/rooms/{id}/ws",
+        ["rooms", _, "events"] => "/rooms/{id}/events",
+        ["history"] => "/history",
+        ["history", "verify"] => "/history/verify",
+        ["fair", "commit"] => "/fair/commit",
+        ["fair", "roll"] => "/fair/roll",
+        ["verify"] => "/verify",
+        ["accounts"] => "/accounts",
+        ["login"] => "/login",
+        ["logout"] => "/logout",
+        ["me", "dice"] => "/me/dice",
+        ["sets"] => "/sets",
+        ["sets", _] => "/sets/{id}",
+        ["s", _] => "/s/{id}",
+        ["metrics"] => "/metrics",
+        ["healthz"] => "/healthz",
+        ["readyz"] => "/readyz",
+        _ => "static",
+    }
+}
+
+/// `GET /metrics`, in the Prometheus text format.
+pub fn routes(rooms: Arc<Rooms>) -> BoxedFilter<(Response,)> {
+    warp::filters::method::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(move || {
+            METRICS.rooms.set(rooms.len() as i64);
+
+            let encoder = TextEncoder::new();
+            let mut body = Vec::new();
+            encoder
+                .encode(&METRICS.registry.gather(), &mut body)
+                .unwrap();
+
+            warp::reply::with_header(body, CONTENT_TYPE, encoder.format_type()).into_response()
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ratelimit::Rate;
+
+    use std::time::Duration;
+
+    #[test]
+    fn route_leaves_out_ids() {
+        assert_eq!("/roll/{roll}", route("/roll/3d6"));
+        assert_eq!("/rooms/{id}/ws", route("/rooms/table/ws"));
+        assert_eq!("/sets/{id}", route("/sets/a1B2c3D4"));
+        assert_eq!("/s/{id}", route("/s/a1B2c3D4"));
+        assert_eq!("/healthz", route("/healthz"));
+        assert_eq!("/readyz", route("/readyz"));
+        assert_eq!("/dice/batch", route("/dice/batch"));
+        assert_eq!("static", route("/"));
+        assert_eq!("static", route("/main.0123abcd.js"));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_reported() {
+        assert!(parse("3d6").is_ok());
+        assert!(parse("3d6q").is_err());
+
+        let rooms = Arc::new(Rooms::new(Duration::from_secs(10), Rate::default()));
+        let response = warp::test::request()
+            .path("/metrics")
+            .reply(&routes(rooms))
+            .await;
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+
+        assert!(body.contains("dicast_parse_errors_total{kind=\"invalid_token\"}"));
+        assert!(body.contains("dicast_dice_count_bucket{le=\"3\"}"));
+        assert!(body.contains("dicast_dice_sides_bucket{le=\"6\"}"));
+        assert!(body.contains("dicast_rooms 0"));
+    }
+}
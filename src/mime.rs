@@ -1,11 +1,20 @@
-#![allow(dead_code)]
+// Types only found by walking the static directory go unused when the
+// frontend is embedded.
+#![cfg_attr(feature = "embed", allow(dead_code))]
 
 use std::fmt;
 
 const HTML: &str = "text/html; charset=utf-8";
 const CSS: &str = "text/css; charset=utf-8";
 const JS: &str = "text/javascript; charset=utf-8";
-const WASM: &str = "application/wasm; charset=utf-8";
+const WASM: &str = "application/wasm";
+const JSON: &str = "application/json";
+const SVG: &str = "image/svg+xml";
+const PNG: &str = "image/png";
+const ICO: &str = "image/x-icon";
+const TEXT: &str = "text/plain; charset=utf-8";
+const MARKDOWN: &str = "text/markdown; charset=utf-8";
+const BINARY: &str = "application/octet-stream";
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Mime {
@@ -13,6 +22,39 @@ pub enum Mime {
     Css,
     Js,
     Wasm,
+    Json,
+    Svg,
+    Png,
+    Ico,
+    Text,
+    Markdown,
+    Binary,
+}
+
+impl Mime {
+    /// Guesses the type of a file from its extension.
+    pub fn from_path(path: &str) -> Mime {
+        let extension = path.rsplit('.').next().unwrap_or_default();
+
+        match extension.to_ascii_lowercase().as_str() {
+            "html" | "htm" => Mime::Html,
+            "css" => Mime::Css,
+            "js" | "mjs" => Mime::Js,
+            "wasm" => Mime::Wasm,
+            "json" => Mime::Json,
+            "svg" => Mime::Svg,
+            "png" => Mime::Png,
+            "ico" => Mime::Ico,
+            "txt" => Mime::Text,
+            "md" => Mime::Markdown,
+            _ => Mime::Binary,
+        }
+    }
+
+    /// Whether the type is worth compressing.
+    pub fn is_compressible(&self) -> bool {
+        !matches!(*self, Mime::Png | Mime::Binary)
+    }
 }
 
 impl fmt::Display for Mime {
@@ -22,6 +64,13 @@ impl fmt::Display for Mime {
             Mime::Css => CSS,
             Mime::Js => JS,
             Mime::Wasm => WASM,
+            Mime::Json => JSON,
+            Mime::Svg => SVG,
+            Mime::Png => PNG,
+            Mime::Ico => ICO,
+            Mime::Text => TEXT,
+            Mime::Markdown => MARKDOWN,
+            Mime::Binary => BINARY,
         };
 
         write!(f, "{}", string)
@@ -0,0 +1,180 @@
+use serde_derive::Deserialize;
+use thiserror::Error;
+use warp::http::HeaderMap;
+use warp::Filter;
+
+use std::convert::{Infallible, TryFrom};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_REAL_IP: &str = "x-real-ip";
+
+/// A peer whose forwarding headers, such as `X-Forwarded-For`, are
+/// believed.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum TrustedProxy {
+    Ip(IpAddr),
+    /// Whatever connects over the Unix domain socket the server listens
+    /// on, given as `unix`.
+    Unix,
+}
+
+impl FromStr for TrustedProxy {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<TrustedProxy, ProxyError> {
+        if s == "unix" {
+            return Ok(TrustedProxy::Unix);
+        }
+
+        s.parse()
+            .map(TrustedProxy::Ip)
+            .map_err(|_| ProxyError::Invalid(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for TrustedProxy {
+    type Error = ProxyError;
+
+    fn try_from(s: String) -> Result<TrustedProxy, ProxyError> {
+        s.parse()
+    }
+}
+
+/// The client's IP, set on requests by `AccessLog`. Taken from the
+/// forwarding headers when a trusted proxy sent the request.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+/// The proxies the server is run behind.
+#[derive(Debug, Clone, Default)]
+pub struct Proxies {
+    trusted: Vec<TrustedProxy>,
+}
+
+impl Proxies {
+    pub fn new(trusted: Vec<TrustedProxy>) -> Proxies {
+        Proxies { trusted }
+    }
+
+    /// Whether a peer's forwarding headers are believed. Peers without an
+    /// address connected over a Unix socket.
+    pub fn trusts(&self, peer: Option<SocketAddr>) -> bool {
+        match peer {
+            Some(peer) => self.trusts_ip(peer.ip()),
+            None => self.trusted.contains(&TrustedProxy::Unix),
+        }
+    }
+
+    fn trusts_ip(&self, ip: IpAddr) -> bool {
+        self.trusted.contains(&TrustedProxy::Ip(ip))
+    }
+
+    /// The client behind a request. For a trusted proxy, that is the last
+    /// address in `X-Forwarded-For` that no trusted proxy added, or else
+    /// `X-Real-IP`. For anyone else, it is the peer.
+    pub fn client_ip(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer_ip = peer.map(|peer| peer.ip());
+        if !self.trusts(peer) {
+            return peer_ip;
+        }
+
+        // Proxies append the address they saw, so anything before the
+        // last untrusted address may have been made up by the client.
+        let forwarded = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .rev()
+            .find(|ip| !matches!(ip, Some(ip) if self.trusts_ip(*ip)));
+
+        match forwarded {
+            Some(Some(ip)) => Some(ip),
+            // An address that doesn't parse can't be told apart from
+            // others, so it is counted as the proxy's.
+            Some(None) => peer_ip,
+            None => headers
+                .get(X_REAL_IP)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|ip| ip.trim().parse().ok())
+                .or(peer_ip),
+        }
+    }
+}
+
+/// The client's IP, as `AccessLog` found it, or else the peer's.
+pub fn client_ip() -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
+    crate::listen::remote()
+        .and(warp::ext::optional::<ClientIp>())
+        .map(|peer: Option<SocketAddr>, client: Option<ClientIp>| match client {
+            Some(client) => client.0,
+            None => peer.map(|peer| peer.ip()),
+        })
+}
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("Expected an IP address or unix, got {0:?}")]
+    Invalid(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn client_ip_only_from_trusted_proxies() {
+        let proxies = Proxies::new(vec!["10.0.0.1".parse().unwrap(), TrustedProxy::Unix]);
+        let proxy = Some(SocketAddr::from(([10, 0, 0, 1], 1234)));
+        let stranger = Some(SocketAddr::from(([192, 0, 2, 9], 1234)));
+        let forwarded = headers(&[(X_FORWARDED_FOR, "203.0.113.7")]);
+
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        assert_eq!(ip("203.0.113.7"), proxies.client_ip(proxy, &forwarded));
+        assert_eq!(ip("203.0.113.7"), proxies.client_ip(None, &forwarded));
+        assert_eq!(ip("192.0.2.9"), proxies.client_ip(stranger, &forwarded));
+        assert_eq!(ip("10.0.0.1"), proxies.client_ip(proxy, &HeaderMap::new()));
+        assert_eq!(None, Proxies::default().client_ip(None, &forwarded));
+    }
+
+    #[test]
+    fn client_ip_skips_spoofed_and_proxy_addresses() {
+        let proxies = Proxies::new(vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()]);
+        let proxy = Some(SocketAddr::from(([10, 0, 0, 1], 1234)));
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        let spoofed = headers(&[(X_FORWARDED_FOR, "1.2.3.4, 203.0.113.7, 10.0.0.2")]);
+        assert_eq!(ip("203.0.113.7"), proxies.client_ip(proxy, &spoofed));
+
+        let split = headers(&[(X_FORWARDED_FOR, "1.2.3.4"), (X_FORWARDED_FOR, "203.0.113.7")]);
+        assert_eq!(ip("203.0.113.7"), proxies.client_ip(proxy, &split));
+
+        let garbled = headers(&[(X_FORWARDED_FOR, "1.2.3.4, nonsense")]);
+        assert_eq!(ip("10.0.0.1"), proxies.client_ip(proxy, &garbled));
+
+        let real = headers(&[(X_REAL_IP, "203.0.113.8")]);
+        assert_eq!(ip("203.0.113.8"), proxies.client_ip(proxy, &real));
+    }
+
+    #[test]
+    fn trusted_proxy_parses() {
+        assert_eq!(TrustedProxy::Unix, "unix".parse().unwrap());
+        assert_eq!(
+            TrustedProxy::Ip("::1".parse().unwrap()),
+            "::1".parse().unwrap()
+        );
+        assert!("10.0.0.0/8".parse::<TrustedProxy>().is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use warp::http::StatusCode;
+use warp::{Filter, Rejection};
+
+use crate::error::ApiError;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often buckets that have filled back up are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where a rate limiter gets the time from, so tests can set it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// How fast requests may come: `burst` at once, then `per_minute`.
+/// A `per_minute` of 0 turns the limit off.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rate {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl Rate {
+    fn per_second(self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+
+    fn capacity(self) -> f64 {
+        f64::from(self.burst.max(1))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate.per_second()).min(rate.capacity());
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct Buckets<K> {
+    buckets: HashMap<K, Bucket>,
+    pruned: Instant,
+}
+
+/// A token bucket per key, each holding up to `burst` tokens and filling
+/// at `per_minute`. Every request takes a token.
+pub struct RateLimiter<K> {
+    rate: Rate,
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<Buckets<K>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(rate: Rate, clock: Arc<dyn Clock>) -> RateLimiter<K> {
+        let pruned = clock.now();
+
+        RateLimiter {
+            rate,
+            clock,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned,
+            }),
+        }
+    }
+
+    /// Takes a token from the key's bucket, failing with a 429 that says
+    /// when to retry if it is empty.
+    pub fn check(&self, key: K) -> Result<(), ApiError> {
+        if self.rate.per_minute == 0 {
+            return Ok(());
+        }
+
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // A full bucket is no different from a new one, so they can go.
+        if now.saturating_duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+            let rate = self.rate;
+            buckets.buckets.retain(|_, bucket| {
+                bucket.refill(rate, now);
+                bucket.tokens < rate.capacity()
+            });
+            buckets.pruned = now;
+        }
+
+        let bucket = buckets.buckets.entry(key).or_insert(Bucket {
+            tokens: self.rate.capacity(),
+            updated: now,
+        });
+        bucket.refill(self.rate, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let retry_after = (((1.0 - bucket.tokens) / self.rate.per_second()).ceil() as u64).max(1);
+        let unit = if retry_after == 1 {
+            "second"
+        } else {
+            "seconds"
+        };
+
+        Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            format!("Too many requests. Try again in {} {}.", retry_after, unit),
+        )
+        .with_retry_after(retry_after))
+    }
+}
+
+/// Rejects clients that go over their IP's rate. Requests whose client
+/// isn't known, such as those over a Unix socket from an untrusted proxy,
+/// share a bucket rather than going unlimited.
+pub fn by_ip(
+    limiter: Arc<RateLimiter<Option<IpAddr>>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    crate::proxy::client_ip()
+        .and_then(move |ip: Option<IpAddr>| {
+            let result = limiter.check(ip);
+
+            async move { result.map_err(warp::reject::custom) }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clock that only moves when told to.
+    struct MockClock(Mutex<Instant>);
+
+    impl MockClock {
+        fn new() -> Arc<MockClock> {
+            Arc::new(MockClock(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    const RATE: Rate = Rate {
+        per_minute: 60,
+        burst: 3,
+    };
+
+    #[test]
+    fn limiter_allows_bursts_then_refills() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(RATE, clock.clone());
+
+        for _ in 0..3 {
+            limiter.check("a").unwrap();
+        }
+        let error = limiter.check("a").unwrap_err();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, error.status);
+        assert_eq!(Some(1), error.retry_after);
+
+        // Other keys have buckets of their own.
+        limiter.check("b").unwrap();
+
+        clock.advance(Duration::from_millis(500));
+        assert!(limiter.check("a").is_err());
+        clock.advance(Duration::from_millis(500));
+        limiter.check("a").unwrap();
+        assert!(limiter.check("a").is_err());
+
+        // Buckets never fill past the burst.
+        clock.advance(Duration::from_secs(60));
+        for _ in 0..3 {
+            limiter.check("a").unwrap();
+        }
+        assert!(limiter.check("a").is_err());
+    }
+
+    #[test]
+    fn limiter_retry_after() {
+        let clock = MockClock::new();
+        let rate = Rate {
+            per_minute: 6,
+            burst: 1,
+        };
+        let limiter = RateLimiter::new(rate, clock.clone());
+
+        limiter.check(1).unwrap();
+        assert_eq!(Some(10), limiter.check(1).unwrap_err().retry_after);
+
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(Some(6), limiter.check(1).unwrap_err().retry_after);
+    }
+
+    #[test]
+    fn limiter_prunes_full_buckets() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(RATE, clock.clone());
+
+        limiter.check(1).unwrap();
+        clock.advance(PRUNE_INTERVAL);
+        limiter.check(2).unwrap();
+
+        assert_eq!(1, limiter.buckets.lock().unwrap().buckets.len());
+    }
+
+    #[test]
+    fn limiter_can_be_off() {
+        let limiter = RateLimiter::new(Rate::default(), MockClock::new());
+
+        for _ in 0..1000 {
+            limiter.check(1).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn by_ip_sends_retry_after() {
+        let clock = MockClock::new();
+        let rate = Rate {
+            per_minute: 60,
+            burst: 1,
+        };
+        let limiter = Arc::new(RateLimiter::new(rate, clock.clone()));
+        let routes = by_ip(limiter)
+            .map(warp::reply)
+            .recover(crate::error::handle_rejection);
+
+        let request = |ip: [u8; 4]| {
+            warp::test::request()
+                .remote_addr((ip, 1234).into())
+                .reply(&routes)
+        };
+
+        assert_eq!(StatusCode::OK, request([10, 0, 0, 1]).await.status());
+
+        let response = request([10, 0, 0, 1]).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("1", response.headers()["retry-after"]);
+
+        assert_eq!(StatusCode::OK, request([10, 0, 0, 2]).await.status());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(StatusCode::OK, request([10, 0, 0, 1]).await.status());
+    }
+
+    #[tokio::test]
+    async fn by_ip_limits_unknown_clients_together() {
+        let rate = Rate {
+            per_minute: 60,
+            burst: 1,
+        };
+        let limiter = Arc::new(RateLimiter::new(rate, MockClock::new()));
+        let routes = by_ip(limiter)
+            .map(warp::reply)
+            .recover(crate::error::handle_rejection);
+
+        let response = warp::test::request().reply(&routes).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let response = warp::test::request().reply(&routes).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+    }
+}
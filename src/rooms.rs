@@ -0,0 +1,1231 @@
+use futures::{SinkExt, StreamExt};
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::ws::{Message, WebSocket, Ws};
+use warp::Filter;
+
+use dice::dice::{Breakdown, DiceRoller};
+
+use crate::config::Limits;
+use crate::error::{ApiError, ErrorBody, ForWarp};
+use crate::history::{Entry, History, Reveal};
+use crate::metrics::{self, Connection};
+use crate::ratelimit::{Rate, RateLimiter, SystemClock};
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const MAX_ROOMS: usize = 10_000;
+const MAX_ID_LEN: usize = 64;
+const MAX_NAME_LEN: usize = 32;
+/// Every die of a room roll is sent to every player, so rolls are kept
+/// far smaller than elsewhere.
+const MAX_ROOM_DICE: i64 = 1_000;
+/// How many events a slow player may fall behind by before missing some.
+const EVENT_CAPACITY: usize = 64;
+/// The longest rooms are left unchecked for expiry.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How many hidden rolls a room keeps for revealing. Older ones can no
+/// longer be revealed.
+const MAX_HIDDEN_ROLLS: usize = 100;
+/// Players are numbered from one, so watchers see only what everyone does.
+const WATCHER: u64 = 0;
+/// The events sent to watchers.
+const FEED_EVENTS: &[&str] = &["roll", "hidden_roll", "reveal"];
+/// How often closing checks whether every player has left.
+const LEAVE_POLL: Duration = Duration::from_millis(50);
+
+const MESSAGE_HINT: &str = "Send a JSON message such as {\"type\": \"roll\", \"roll\": \"1d20\"}.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Runs the room, seeing every roll and revealing hidden ones.
+    Gm,
+    Player,
+}
+
+/// Who a roll is shown to. Everyone else only learns that it was made.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Public,
+    /// Shown to the GM and the roller.
+    Gm,
+    /// Shown to the GM alone, not even the roller.
+    Blind,
+}
+
+/// What players send to a room.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Roll {
+        roll: String,
+        #[serde(default)]
+        visibility: Visibility,
+    },
+    /// Shows a hidden roll to everyone. Only the GM may reveal.
+    Reveal { id: u64 },
+}
+
+/// What a room sends to its players. Errors only go to the player that
+/// caused them.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomEvent {
+    /// Sent to a player alone when they join. The GM is given the room's
+    /// token, with which they can join again as GM.
+    Welcome {
+        role: Role,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    Join {
+        name: String,
+    },
+    Leave {
+        name: String,
+    },
+    Roll {
+        name: String,
+        roll: String,
+        results: Vec<Breakdown>,
+        visibility: Visibility,
+        /// Identifies hidden rolls, so that they can be revealed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+    },
+    /// Sent in place of a roll to those not shown it.
+    HiddenRoll {
+        name: String,
+        visibility: Visibility,
+        id: u64,
+        commitment: String,
+    },
+    Reveal {
+        id: u64,
+        name: String,
+        roll: String,
+        results: Vec<Breakdown>,
+        salt: String,
+    },
+    Error(ErrorBody),
+}
+
+impl RoomEvent {
+    fn kind(&self) -> &'static str {
+        match self {
+            RoomEvent::Welcome { .. } => "welcome",
+            RoomEvent::Join { .. } => "join",
+            RoomEvent::Leave { .. } => "leave",
+            RoomEvent::Roll { .. } => "roll",
+            RoomEvent::HiddenRoll { .. } => "hidden_roll",
+            RoomEvent::Reveal { .. } => "reveal",
+            RoomEvent::Error(_) => "error",
+        }
+    }
+
+    fn to_message(&self) -> Message {
+        Message::text(serde_json::to_string(self).expect("room events serialize"))
+    }
+}
+
+/// A hidden roll, kept until it is revealed.
+#[derive(Debug, Clone)]
+pub struct HiddenRoll {
+    pub name: String,
+    pub roll: String,
+    pub results: Vec<Breakdown>,
+    pub salt: String,
+    /// The id of the roll's history record.
+    pub record: u64,
+}
+
+impl HiddenRoll {
+    /// The hex SHA-256 of the salt followed by the results as JSON, which
+    /// players can check once the roll is revealed.
+    pub fn commitment(&self) -> String {
+        HiddenRoll::commit(&self.salt, &self.results)
+    }
+
+    fn commit(salt: &str, results: &[Breakdown]) -> String {
+        let results = serde_json::to_string(results).expect("breakdowns serialize");
+
+        hex::encode(Sha256::new().chain(salt).chain(results).finalize())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Audience {
+    Everyone,
+    Gm,
+    /// The GM and the player with this id.
+    GmAnd(u64),
+}
+
+/// An event as broadcast to a room, filtered by each player on receipt.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    /// The type of the event, the same for everyone.
+    kind: &'static str,
+    message: Message,
+    audience: Audience,
+    /// What everyone outside the audience gets instead, if anything.
+    redacted: Option<Message>,
+}
+
+impl Envelope {
+    pub fn everyone(event: &RoomEvent) -> Self {
+        Envelope {
+            kind: event.kind(),
+            message: event.to_message(),
+            audience: Audience::Everyone,
+            redacted: None,
+        }
+    }
+
+    pub fn hidden(event: &RoomEvent, audience: Audience, redacted: &RoomEvent) -> Self {
+        Envelope {
+            kind: event.kind(),
+            message: event.to_message(),
+            audience,
+            redacted: Some(redacted.to_message()),
+        }
+    }
+
+    /// The message a player receives, if any.
+    pub fn open(&self, player: u64, role: Role) -> Option<&Message> {
+        let shown = match self.audience {
+            Audience::Everyone => true,
+            Audience::Gm => role == Role::Gm,
+            Audience::GmAnd(id) => role == Role::Gm || id == player,
+        };
+
+        if shown {
+            Some(&self.message)
+        } else {
+            self.redacted.as_ref()
+        }
+    }
+}
+
+/// A player's place in a room.
+#[derive(Debug)]
+pub struct Seat {
+    pub events: broadcast::Receiver<Envelope>,
+    pub player: u64,
+    pub role: Role,
+    /// The room's token, given to the GM.
+    pub token: Option<String>,
+    /// Held until the player leaves, so that closing can wait for them.
+    present: Arc<()>,
+    /// Held until the player leaves, so that the room stays open for them.
+    seated: Arc<()>,
+}
+
+#[derive(Debug)]
+struct Room {
+    events: broadcast::Sender<Envelope>,
+    /// Counts the room's players, through their seats' clones of it.
+    /// Watchers aren't counted, so they can't keep a room open.
+    players: Arc<()>,
+    /// Set once a player claims the room as GM.
+    token: Option<String>,
+    hidden: VecDeque<(u64, HiddenRoll)>,
+    next_player: u64,
+    next_roll: u64,
+    last_active: Instant,
+}
+
+/// The rooms in play, held in memory. A room opens when its first player
+/// joins, who becomes its GM, and closes once it has been empty for the
+/// idle time.
+pub struct Rooms {
+    rooms: Mutex<HashMap<String, Room>>,
+    idle: Duration,
+    /// Shared by everyone in a room, so one player can't flood the rest.
+    rolls: RateLimiter<String>,
+    /// Set once the server starts shutting down.
+    closed: AtomicBool,
+    /// Counts the seats taken, through the seats' clones of it.
+    present: Arc<()>,
+}
+
+impl Rooms {
+    pub fn new(idle: Duration, roll_rate: Rate) -> Self {
+        Rooms {
+            rooms: Mutex::new(HashMap::new()),
+            idle,
+            rolls: RateLimiter::new(roll_rate, Arc::new(SystemClock)),
+            closed: AtomicBool::new(false),
+            present: Arc::new(()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.lock().unwrap().len()
+    }
+
+    /// Joins a room, opening it if need be. The first player to join
+    /// claims the room as GM, as does anyone holding its token.
+    pub fn join(&self, id: &str, token: Option<&str>, now: Instant) -> Result<Seat, ApiError> {
+        self.check_open()?;
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = open(&mut rooms, id, now)?;
+
+        let role = match (&room.token, token) {
+            (None, _) => {
+                room.token = Some(random_hex());
+                Role::Gm
+            }
+            (Some(room_token), Some(token)) if room_token == token => Role::Gm,
+            (Some(_), Some(_)) => {
+                return Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    "wrong_token",
+                    "The token is not this room's.",
+                ))
+            }
+            (Some(_), None) => Role::Player,
+        };
+
+        room.last_active = now;
+        room.next_player += 1;
+
+        Ok(Seat {
+            events: room.events.subscribe(),
+            player: room.next_player,
+            role,
+            token: room.token.clone().filter(|_| role == Role::Gm),
+            present: self.present.clone(),
+            seated: room.players.clone(),
+        })
+    }
+
+    /// Follows an open room's events without joining it. Only players
+    /// open rooms, or keep them open, so that anyone can't fill the server
+    /// with them.
+    pub fn watch(&self, id: &str) -> Result<broadcast::Receiver<Envelope>, ApiError> {
+        self.check_open()?;
+        let rooms = self.rooms.lock().unwrap();
+        let room = rooms.get(id).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "unknown_room",
+                "The room isn't open. Rooms open when their first player joins.",
+            )
+        })?;
+
+        Ok(room.events.subscribe())
+    }
+
+    /// Sends an event to everyone in a room.
+    pub fn broadcast(&self, id: &str, envelope: Envelope, now: Instant) {
+        let mut rooms = self.rooms.lock().unwrap();
+
+        if let Some(room) = rooms.get_mut(id) {
+            room.last_active = now;
+            // Fails only when nobody is listening.
+            let _ = room.events.send(envelope);
+        }
+    }
+
+    /// Keeps a hidden roll for revealing, returning its id.
+    pub fn hide(&self, id: &str, roll: HiddenRoll) -> Result<u64, ApiError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.get_mut(id).ok_or_else(room_closed)?;
+
+        room.next_roll += 1;
+        if room.hidden.len() >= MAX_HIDDEN_ROLLS {
+            room.hidden.pop_front();
+        }
+        room.hidden.push_back((room.next_roll, roll));
+
+        Ok(room.next_roll)
+    }
+
+    /// Takes a hidden roll so that it can be revealed.
+    pub fn reveal(&self, id: &str, roll: u64) -> Result<HiddenRoll, ApiError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.get_mut(id).ok_or_else(room_closed)?;
+
+        let index = room
+            .hidden
+            .iter()
+            .position(|(id, _)| *id == roll)
+            .ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    "unknown_roll",
+                    format!("No hidden roll {} is waiting to be revealed.", roll),
+                )
+            })?;
+
+        Ok(room.hidden.remove(index).expect("index is in bounds").1)
+    }
+
+    /// Closes rooms that have had no players for the idle time, returning
+    /// how many were closed. Their watchers are sent away.
+    pub fn expire(&self, now: Instant) -> usize {
+        let mut rooms = self.rooms.lock().unwrap();
+        let before = rooms.len();
+
+        rooms.retain(|_, room| {
+            Arc::strong_count(&room.players) > 1
+                || now.saturating_duration_since(room.last_active) < self.idle
+        });
+
+        before - rooms.len()
+    }
+
+    /// Closes every room, sending their players and watchers away, and
+    /// turns away anyone new. Waits up to `timeout` for the players to
+    /// leave, returning whether they all did.
+    pub async fn close(&self, timeout: Duration) -> bool {
+        self.closed.store(true, Ordering::SeqCst);
+        // Dropping the rooms' senders ends every player's and watcher's
+        // stream of events.
+        self.rooms.lock().unwrap().clear();
+
+        let start = Instant::now();
+        while Arc::strong_count(&self.present) > 1 {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            tokio::time::delay_for(LEAVE_POLL).await;
+        }
+
+        true
+    }
+
+    fn check_open(&self) -> Result<(), ApiError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "shutting_down",
+                "The server is shutting down.",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds a room, opening it unclaimed if it is not open yet.
+fn open<'a>(
+    rooms: &'a mut HashMap<String, Room>,
+    id: &str,
+    now: Instant,
+) -> Result<&'a mut Room, ApiError> {
+    if !rooms.contains_key(id) && rooms.len() >= MAX_ROOMS {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "too_many_rooms",
+            "Too many rooms are open.",
+        ));
+    }
+
+    Ok(rooms.entry(id.to_string()).or_insert_with(|| Room {
+        events: broadcast::channel(EVENT_CAPACITY).0,
+        players: Arc::new(()),
+        token: None,
+        hidden: VecDeque::new(),
+        next_player: 0,
+        next_roll: 0,
+        last_active: now,
+    }))
+}
+
+fn room_closed() -> ApiError {
+    ApiError::new(StatusCode::GONE, "room_closed", "The room has closed.")
+}
+
+/// 128 random bits, hex encoded, for tokens and salts.
+fn random_hex() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 16]>())
+}
+
+/// Closes idle rooms for as long as the server runs.
+pub async fn expire_idle(rooms: Arc<Rooms>) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL.min(rooms.idle));
+
+    loop {
+        interval.tick().await;
+
+        let expired = rooms.expire(Instant::now());
+        if expired > 0 {
+            log::info!(
+                "Closed {} idle rooms, {} remain open.",
+                expired,
+                rooms.len()
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct RoomQuery {
+    /// The name the player's rolls are shown under.
+    pub name: String,
+    /// The room's token, to join as GM.
+    pub token: Option<String>,
+}
+
+/// The player behind a connection.
+#[derive(Debug, Clone)]
+struct Player {
+    id: u64,
+    name: String,
+    role: Role,
+}
+
+/// `GET /rooms/{id}/ws?name=...&token=...`, which upgrades to a WebSocket,
+/// and `GET /rooms/{id}/events`, a feed of the room's rolls.
+pub fn routes(
+    rooms: Arc<Rooms>,
+    history: Arc<History>,
+    limits: Limits,
+) -> BoxedFilter<(Response,)> {
+    let with_rooms = warp::any().map(move || rooms.clone());
+    let with_history = warp::any().map(move || history.clone());
+
+    let ws = warp::path!("rooms" / String / "ws")
+        .and(warp::query::<RoomQuery>())
+        .and(warp::ws())
+        .and(with_rooms.clone())
+        .and(with_history)
+        .and_then(
+            move |id: String,
+                  query: RoomQuery,
+                  ws: Ws,
+                  rooms: Arc<Rooms>,
+                  history: Arc<History>| async move {
+                upgrade(ws, id, query, rooms, history, limits).for_warp()
+            },
+        );
+
+    let events = warp::filters::method::get()
+        .and(warp::path!("rooms" / String / "events"))
+        .and(with_rooms)
+        .and_then(|id: String, rooms: Arc<Rooms>| async move { feed(id, &rooms).for_warp() });
+
+    ws.or(events).unify().boxed()
+}
+
+fn check_id(id: &str) -> Result<(), ApiError> {
+    if id.is_empty()
+        || id.len() > MAX_ID_LEN
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_room",
+            format!(
+                "Room ids are 1 to {} letters, digits, dashes or underscores.",
+                MAX_ID_LEN
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Streams a room's rolls as Server-Sent Events, showing hidden rolls as
+/// players see them.
+fn feed(id: String, rooms: &Rooms) -> Result<Response, ApiError> {
+    check_id(&id)?;
+
+    let events = rooms.watch(&id)?;
+    let stream = futures::stream::unfold(events, |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(envelope) if FEED_EVENTS.contains(&envelope.kind) => {
+                    let data = envelope
+                        .open(WATCHER, Role::Player)
+                        .and_then(|message| message.to_str().ok())
+                        .map(str::to_string);
+
+                    if let Some(data) = data {
+                        let event = (warp::sse::event(envelope.kind), warp::sse::data(data));
+                        return Some((Ok::<_, Infallible>(event), events));
+                    }
+                }
+                Ok(_) | Err(broadcast::RecvError::Lagged(_)) => continue,
+                Err(broadcast::RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)).into_response())
+}
+
+fn upgrade(
+    ws: Ws,
+    id: String,
+    query: RoomQuery,
+    rooms: Arc<Rooms>,
+    history: Arc<History>,
+    limits: Limits,
+) -> Result<Response, ApiError> {
+    let name = query.name.trim().to_string();
+
+    check_id(&id)?;
+
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_name",
+            format!("Names are 1 to {} characters.", MAX_NAME_LEN),
+        ));
+    }
+
+    Ok(ws
+        .on_upgrade(move |socket| play(socket, id, name, query.token, rooms, history, limits))
+        .into_response())
+}
+
+/// Relays a player's rolls to the room, and the room's events back to
+/// the player, until either side leaves.
+async fn play(
+    socket: WebSocket,
+    id: String,
+    name: String,
+    token: Option<String>,
+    rooms: Arc<Rooms>,
+    history: Arc<History>,
+    limits: Limits,
+) {
+    let _connection = Connection::open();
+    let (mut tx, mut rx) = socket.split();
+
+    let Seat {
+        mut events,
+        player,
+        role,
+        token,
+        present: _present,
+        seated: _seated,
+    } = match rooms.join(&id, token.as_deref(), Instant::now()) {
+        Ok(seat) => seat,
+        Err(e) => {
+            let _ = tx.send(RoomEvent::Error(e.body).to_message()).await;
+            return;
+        }
+    };
+
+    let player = Player {
+        id: player,
+        name,
+        role,
+    };
+    let welcome = RoomEvent::Welcome { role, token };
+
+    if tx.send(welcome.to_message()).await.is_err() {
+        return;
+    }
+
+    log::info!("{:?} joined room {:?} as {:?}", player.name, id, role);
+    let join = RoomEvent::Join {
+        name: player.name.clone(),
+    };
+    rooms.broadcast(&id, Envelope::everyone(&join), Instant::now());
+
+    loop {
+        tokio::select! {
+            message = rx.next() => {
+                let message = match message {
+                    Some(Ok(message)) if !message.is_close() => message,
+                    _ => break,
+                };
+
+                // Pings and binary messages carry no rolls.
+                let text = match message.to_str() {
+                    Ok(text) => text,
+                    Err(_) => continue,
+                };
+
+                match handle(text, &player, &id, &rooms, &history, &limits) {
+                    Ok(envelope) => rooms.broadcast(&id, envelope, Instant::now()),
+                    Err(e) => {
+                        if tx.send(RoomEvent::Error(e.body).to_message()).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            event = events.recv() => match event {
+                Ok(envelope) => {
+                    if let Some(message) = envelope.open(player.id, player.role) {
+                        if tx.send(message.clone()).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(broadcast::RecvError::Lagged(missed)) => {
+                    log::warn!("{:?} missed {} events in room {:?}", player.name, missed, id);
+                }
+                Err(broadcast::RecvError::Closed) => {
+                    // The room was closed under the player.
+                    let _ = tx.send(Message::close()).await;
+                    break;
+                }
+            },
+        }
+    }
+
+    drop(events);
+    log::info!("{:?} left room {:?}", player.name, id);
+    let leave = RoomEvent::Leave { name: player.name };
+    rooms.broadcast(&id, Envelope::everyone(&leave), Instant::now());
+}
+
+fn handle(
+    text: &str,
+    player: &Player,
+    room: &str,
+    rooms: &Rooms,
+    history: &History,
+    limits: &Limits,
+) -> Result<Envelope, ApiError> {
+    let message = serde_json::from_str::<ClientMessage>(text).map_err(|e| {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_message", e).with_hint(MESSAGE_HINT)
+    })?;
+
+    match message {
+        ClientMessage::Roll { roll, visibility } => {
+            rooms.rolls.check(room.to_string())?;
+            let results = roll_breakdown(&roll, limits)?;
+
+            let audience = match visibility {
+                Visibility::Public => {
+                    history.record(entry(room, &player.name, &roll, &results, visibility))?;
+
+                    return Ok(Envelope::everyone(&RoomEvent::Roll {
+                        name: player.name.clone(),
+                        roll,
+                        results,
+                        visibility,
+                        id: None,
+                    }));
+                }
+                Visibility::Gm => Audience::GmAnd(player.id),
+                Visibility::Blind => Audience::Gm,
+            };
+
+            let salt = random_hex();
+            let commitment = HiddenRoll::commit(&salt, &results);
+            let record = history.record(Entry {
+                commitment: Some(commitment.clone()),
+                ..entry(room, &player.name, &roll, &results, visibility)
+            })?;
+            let hidden = HiddenRoll {
+                name: player.name.clone(),
+                roll,
+                results,
+                salt,
+                record: record.id,
+            };
+            let id = rooms.hide(room, hidden.clone())?;
+
+            Ok(Envelope::hidden(
+                &RoomEvent::Roll {
+                    name: hidden.name.clone(),
+                    roll: hidden.roll,
+                    results: hidden.results,
+                    visibility,
+                    id: Some(id),
+                },
+                audience,
+                &RoomEvent::HiddenRoll {
+                    name: hidden.name,
+                    visibility,
+                    id,
+                    commitment,
+                },
+            ))
+        }
+        ClientMessage::Reveal { id } => {
+            if player.role != Role::Gm {
+                return Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    "not_gm",
+                    "Only the GM may reveal rolls.",
+                ));
+            }
+
+            // The roll is already on record, so only the reveal is added.
+            let hidden = rooms.reveal(room, id)?;
+            history.record(Entry {
+                roll: hidden.roll.clone(),
+                room: Some(room.to_string()),
+                user: Some(hidden.name.clone()),
+                reveal: Some(Reveal {
+                    id: hidden.record,
+                    commitment: hidden.commitment(),
+                    salt: hidden.salt.clone(),
+                }),
+                ..Entry::default()
+            })?;
+
+            Ok(Envelope::everyone(&RoomEvent::Reveal {
+                id,
+                name: hidden.name,
+                roll: hidden.roll,
+                results: hidden.results,
+                salt: hidden.salt,
+            }))
+        }
+    }
+}
+
+/// A room roll as the history keeps it. Hidden rolls are kept with their
+/// visibility, which keeps them out of history queries until revealed.
+fn entry(
+    room: &str,
+    name: &str,
+    roll: &str,
+    results: &[Breakdown],
+    visibility: Visibility,
+) -> Entry {
+    Entry {
+        roll: roll.to_string(),
+        totals: results.iter().map(|result| result.total).collect(),
+        breakdown: Some(results.to_vec()),
+        room: Some(room.to_string()),
+        user: Some(name.to_string()),
+        visibility,
+        ..Entry::default()
+    }
+}
+
+fn roll_breakdown(roll: &str, limits: &Limits) -> Result<Vec<Breakdown>, ApiError> {
+    let (times, dice) = metrics::parse(roll)?;
+    limits.check(times, &dice)?;
+
+    if times.max(0).saturating_mul(dice.count()) > MAX_ROOM_DICE {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "limit_exceeded",
+            format!(
+                "Rolls in rooms may have at most {} dice in all.",
+                MAX_ROOM_DICE
+            ),
+        ));
+    }
+
+    let mut roller = DiceRoller::new();
+
+    Ok(roller.roll_breakdown_times(&dice, times))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::history::{Query, Record};
+
+    use serde_json::Value;
+    use tokio_tungstenite::tungstenite;
+
+    async fn recv<S, E>(client: &mut S) -> Value
+    where
+        S: futures::Stream<Item = Result<tungstenite::Message, E>> + Unpin,
+        E: std::fmt::Debug,
+    {
+        let message = client.next().await.unwrap().unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    fn history() -> (tempfile::TempDir, Arc<History>) {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(dir.path()).unwrap();
+
+        (dir, Arc::new(history))
+    }
+
+    fn player(id: u64, role: Role) -> Player {
+        Player {
+            id,
+            name: "Ann".to_string(),
+            role,
+        }
+    }
+
+    fn open(envelope: &Envelope, player: u64, role: Role) -> Option<Value> {
+        envelope
+            .open(player, role)
+            .map(|message| serde_json::from_str(message.to_str().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn rooms_expire_when_empty_and_idle() {
+        let rooms = Rooms::new(Duration::from_secs(10), Rate::default());
+        let start = Instant::now();
+
+        let seat = rooms.join("busy", None, start).unwrap();
+        drop(rooms.join("empty", None, start).unwrap());
+
+        assert_eq!(0, rooms.expire(start + Duration::from_secs(5)));
+        assert_eq!(1, rooms.expire(start + Duration::from_secs(10)));
+        assert_eq!(1, rooms.len());
+
+        drop(seat);
+        assert_eq!(1, rooms.expire(start + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn rooms_expire_with_only_watchers() {
+        let rooms = Rooms::new(Duration::from_secs(10), Rate::default());
+        let start = Instant::now();
+
+        drop(rooms.join("stream", None, start).unwrap());
+        let mut watcher = rooms.watch("stream").unwrap();
+
+        assert_eq!(1, rooms.expire(start + Duration::from_secs(10)));
+        assert!(matches!(
+            watcher.try_recv(),
+            Err(broadcast::TryRecvError::Closed)
+        ));
+    }
+
+    #[test]
+    fn rooms_join_roles() {
+        let rooms = Rooms::new(Duration::from_secs(10), Rate::default());
+        let now = Instant::now();
+
+        let gm = rooms.join("table", None, now).unwrap();
+        let token = gm.token.clone().unwrap();
+        assert_eq!(Role::Gm, gm.role);
+
+        let player = rooms.join("table", None, now).unwrap();
+        assert_eq!(Role::Player, player.role);
+        assert_ne!(gm.player, player.player);
+
+        assert_eq!(
+            Role::Gm,
+            rooms.join("table", Some(&token), now).unwrap().role
+        );
+
+        let error = rooms.join("table", Some("guess"), now).unwrap_err();
+        assert_eq!("wrong_token", error.body.code);
+    }
+
+    #[test]
+    fn rooms_watch_only_open_rooms() {
+        let rooms = Rooms::new(Duration::from_secs(10), Rate::default());
+        let now = Instant::now();
+
+        let error = rooms.watch("table").unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, error.status);
+        assert_eq!(0, rooms.len());
+
+        let seat = rooms.join("table", None, now).unwrap();
+        assert_ne!(WATCHER, seat.player);
+        let _watcher = rooms.watch("table").unwrap();
+        assert_eq!(1, rooms.len());
+    }
+
+    #[test]
+    fn envelope_open() {
+        let event = RoomEvent::Join {
+            name: "Ann".to_string(),
+        };
+        let redacted = RoomEvent::Leave {
+            name: "Ann".to_string(),
+        };
+
+        let public = Envelope::everyone(&event);
+        assert_eq!("join", open(&public, 1, Role::Player).unwrap()["type"]);
+
+        let gm = Envelope::hidden(&event, Audience::GmAnd(1), &redacted);
+        assert_eq!("join", open(&gm, 0, Role::Gm).unwrap()["type"]);
+        assert_eq!("join", open(&gm, 1, Role::Player).unwrap()["type"]);
+        assert_eq!("leave", open(&gm, 2, Role::Player).unwrap()["type"]);
+
+        let blind = Envelope::hidden(&event, Audience::Gm, &redacted);
+        assert_eq!("join", open(&blind, 0, Role::Gm).unwrap()["type"]);
+        assert_eq!("leave", open(&blind, 1, Role::Player).unwrap()["type"]);
+    }
+
+    #[test]
+    fn handle_roll() {
+        let rooms = Rooms::new(Duration::from_secs(10), Rate::default());
+        let (_dir, history) = history();
+        let limits = Limits::default();
+        let ann = player(0, Role::Player);
+
+        let envelope = handle(
+            r#"{"type": "roll", "roll": "2x 3d6 s1"}"#,
+            &ann,
+            "table",
+            &rooms,
+            &history,
+            &limits,
+        )
+        .unwrap();
+        let event = open(&envelope, 1, Role::Player).unwrap();
+        assert_eq!("Ann", event["name"]);
+        assert_eq!("public", event["visibility"]);
+        assert_eq!(2, event["results"].as_array().unwrap().len());
+        assert_eq!(3, event["results"][0]["faces"].as_array().unwrap().len());
+        assert_eq!(1, event["results"][0]["dropped"].as_array().unwrap().len());
+
+        let error = handle(
+            r#"{"roll": "1d6"}"#,
+            &ann,
+            "table",
+            &rooms,
+            &history,
+            &limits,
+        )
+        .unwrap_err();
+        assert_eq!("invalid_message", error.body.code);
+
+        let error = handle(
+            r#"{"type": "roll", "roll": "2000d6"}"#,
+            &ann,
+            "table",
+            &rooms,
+            &history,
+            &limits,
+        )
+        .unwrap_err();
+        assert_eq!("limit_exceeded", error.body.code);
+    }
+
+    #[test]
+    fn handle_rate_limits_rooms() {
+        let rate = Rate {
+            per_minute: 1,
+            burst: 1,
+        };
+        let rooms = Rooms::new(Duration::from_secs(10), rate);
+        let (_dir, history) = history();
+        let limits = Limits::default();
+        let ann = player(0, Role::Player);
+        let roll = |room| {
+            handle(
+                r#"{"type": "roll", "roll": "1d6"}"#,
+                &ann,
+                room,
+                &rooms,
+                &history,
+                &limits,
+            )
+        };
+
+        roll("table").unwrap();
+        let error = roll("table").unwrap_err();
+        assert_eq!("rate_limited", error.body.code);
+        assert_eq!(Some(60), error.retry_after);
+
+        roll("other").unwrap();
+    }
+
+    #[test]
+    fn handle_hidden_roll_and_reveal() {
+        let rooms = Rooms::new(Duration::from_secs(10), Rate::default());
+        let (dir, history) = history();
+        let limits = Limits::default();
+        let seat = rooms.join("table", None, Instant::now()).unwrap();
+        let gm = player(seat.player, Role::Gm);
+        let ann = player(seat.player + 1, Role::Player);
+
+        let envelope = handle(
+            r#"{"type": "roll", "roll": "1d20", "visibility": "blind"}"#,
+            &ann,
+            "table",
+            &rooms,
+            &history,
+            &limits,
+        )
+        .unwrap();
+
+        let hidden = open(&envelope, ann.id, Role::Player).unwrap();
+        assert_eq!("hidden_roll", hidden["type"]);
+        assert!(hidden.get("results").is_none());
+        assert_eq!("roll", open(&envelope, gm.id, Role::Gm).unwrap()["type"]);
+        assert!(history.query(&Query::default()).unwrap().records.is_empty());
+
+        let reveal = format!(r#"{{"type": "reveal", "id": {}}}"#, hidden["id"]);
+        let error = handle(&reveal, &ann, "table", &rooms, &history, &limits).unwrap_err();
+        assert_eq!("not_gm", error.body.code);
+
+        let envelope = handle(&reveal, &gm, "table", &rooms, &history, &limits).unwrap();
+        let revealed = open(&envelope, ann.id, Role::Player).unwrap();
+        let salt = revealed["salt"].as_str().unwrap();
+        let results = serde_json::from_value::<Vec<Breakdown>>(revealed["results"].clone())
+            .map(|results| serde_json::to_string(&results).unwrap())
+            .unwrap();
+        let commitment = hex::encode(Sha256::new().chain(salt).chain(results).finalize());
+        assert_eq!(hidden["commitment"], commitment.as_str());
+
+        // The roll is listed once, as it was made.
+        let records = history.query(&Query::default()).unwrap().records;
+        assert_eq!(1, records.len());
+        assert_eq!(Visibility::Blind, records[0].visibility);
+        assert_eq!(Some("Ann"), records[0].user.as_deref());
+        assert_eq!(Some("table"), records[0].room.as_deref());
+        assert_eq!(Some(commitment.as_str()), records[0].commitment.as_deref());
+        assert_eq!(1, records[0].totals.len());
+
+        history.sync().unwrap();
+        let log = std::fs::read_to_string(dir.path().join("history.jsonl")).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(2, lines.len());
+        let record = serde_json::from_str::<Record>(lines[1]).unwrap();
+        assert!(record.totals.is_empty());
+        assert_eq!(records[0].id, record.reveal.as_ref().unwrap().id);
+        assert_eq!(commitment, record.reveal.unwrap().commitment);
+
+        let error = handle(&reveal, &gm, "table", &rooms, &history, &limits).unwrap_err();
+        assert_eq!("unknown_roll", error.body.code);
+    }
+
+    #[tokio::test]
+    async fn rolls_are_broadcast() {
+        use tungstenite::Message;
+
+        let rooms = Arc::new(Rooms::new(Duration::from_secs(60), Rate::default()));
+        let (_dir, history) = history();
+        let (addr, server) = warp::serve(routes(rooms.clone(), history, Limits::default()))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let url = |name: &str| format!("ws://{}/rooms/table/ws?name={}", addr, name);
+
+        let (mut gm, _) = tokio_tungstenite::connect_async(url("Gm")).await.unwrap();
+        let welcome = recv(&mut gm).await;
+        assert_eq!("gm", welcome["role"]);
+        assert!(welcome["token"].is_string());
+        assert_eq!("join", recv(&mut gm).await["type"]);
+
+        let (mut bo, _) = tokio_tungstenite::connect_async(url("Bo")).await.unwrap();
+        let welcome = recv(&mut bo).await;
+        assert_eq!("player", welcome["role"]);
+        assert!(welcome.get("token").is_none());
+        assert_eq!("Bo", recv(&mut gm).await["name"]);
+        assert_eq!("Bo", recv(&mut bo).await["name"]);
+
+        bo.send(Message::text(r#"{"type": "roll", "roll": "1d20"}"#))
+            .await
+            .unwrap();
+
+        for client in [&mut gm, &mut bo].iter_mut() {
+            let event = recv(client).await;
+            assert_eq!("roll", event["type"]);
+            assert_eq!("Bo", event["name"]);
+            assert_eq!(1, event["results"].as_array().unwrap().len());
+        }
+
+        gm.send(Message::text(
+            r#"{"type": "roll", "roll": "1d20", "visibility": "gm"}"#,
+        ))
+        .await
+        .unwrap();
+        assert_eq!("roll", recv(&mut gm).await["type"]);
+        assert_eq!("hidden_roll", recv(&mut bo).await["type"]);
+
+        bo.send(Message::text("not json")).await.unwrap();
+        assert_eq!("invalid_message", recv(&mut bo).await["code"]);
+        assert_eq!(1, rooms.len());
+    }
+
+    #[tokio::test]
+    async fn close_sends_players_away() {
+        let rooms = Arc::new(Rooms::new(Duration::from_secs(60), Rate::default()));
+        let (_dir, history) = history();
+        let (addr, server) = warp::serve(routes(rooms.clone(), history, Limits::default()))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let url = format!("ws://{}/rooms/table/ws?name=Ann", addr);
+        let (mut ann, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        assert_eq!("welcome", recv(&mut ann).await["type"]);
+        assert_eq!("join", recv(&mut ann).await["type"]);
+
+        assert!(rooms.close(Duration::from_secs(5)).await);
+        assert!(ann.next().await.unwrap().unwrap().is_close());
+        assert_eq!(0, rooms.len());
+
+        let error = rooms.join("table", None, Instant::now()).unwrap_err();
+        assert_eq!("shutting_down", error.body.code);
+    }
+
+    #[tokio::test]
+    async fn feed_sends_rolls() {
+        use warp::hyper::body::HttpBody;
+
+        let rooms = Arc::new(Rooms::new(Duration::from_secs(60), Rate::default()));
+        let (_dir, history) = history();
+        let (addr, server) = warp::serve(routes(rooms.clone(), history, Limits::default()))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let _seat = rooms.join("table", None, Instant::now()).unwrap();
+
+        let url = format!("http://{}/rooms/table/events", addr);
+        let response = warp::hyper::Client::new()
+            .get(url.parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!("text/event-stream", response.headers()["content-type"]);
+
+        let join = RoomEvent::Join {
+            name: "Ann".to_string(),
+        };
+        let roll = RoomEvent::Roll {
+            name: "Ann".to_string(),
+            roll: "1d20".to_string(),
+            results: vec![],
+            visibility: Visibility::Public,
+            id: None,
+        };
+        rooms.broadcast("table", Envelope::everyone(&join), Instant::now());
+        rooms.broadcast("table", Envelope::everyone(&roll), Instant::now());
+
+        let mut body = response.into_body();
+        let mut text = String::new();
+        while !text.contains("\n\n") {
+            let chunk = body.data().await.unwrap().unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        assert!(text.starts_with("event:roll\ndata:{\"type\":\"roll\""));
+    }
+
+    #[tokio::test]
+    async fn invalid_room_is_rejected() {
+        let rooms = Arc::new(Rooms::new(Duration::from_secs(60), Rate::default()));
+        let (_dir, history) = history();
+
+        let response = warp::test::request()
+            .path("/rooms/no%20spaces/ws?name=Ann")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .filter(&routes(rooms.clone(), history, Limits::default()))
+            .await;
+
+        match response {
+            Err(rejection) => {
+                let error = rejection.find::<ApiError>().unwrap();
+                assert_eq!("invalid_room", error.body.code);
+            }
+            Ok(_) => panic!("expected the room to be rejected"),
+        }
+        assert_eq!(0, rooms.len());
+    }
+}
@@ -0,0 +1,436 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::accounts::{Accounts, SESSION_COOKIE};
+use crate::config::Limits;
+use crate::error::{blocking, ApiError, ForWarp};
+use crate::metrics;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const SETS_FILE: &str = "sets.json";
+const ID_LEN: usize = 8;
+const MAX_SETS_PER_USER: usize = 100;
+const MAX_SET_DICE: usize = 100;
+const MAX_NAME_LEN: usize = 64;
+const MAX_ROLL_LEN: usize = 256;
+
+/// A die in a set. Unlike the frontend's dice, sets carry no outputs.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SetDie {
+    pub name: String,
+    pub roll: String,
+}
+
+/// A named list of dice, shared by its short id.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DiceSet {
+    pub id: String,
+    pub name: String,
+    /// The username of the account that made the set.
+    pub owner: String,
+    pub dice: Vec<SetDie>,
+}
+
+/// The parts of a set that its owner writes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SetRequest {
+    pub name: String,
+    pub dice: Vec<SetDie>,
+}
+
+impl SetRequest {
+    fn check(&self, limits: &Limits) -> Result<(), ApiError> {
+        let name_ok = |name: &str| !name.trim().is_empty() && name.chars().count() <= MAX_NAME_LEN;
+
+        if !name_ok(&self.name) || !self.dice.iter().all(|die| name_ok(&die.name)) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_name",
+                format!("Set and dice names are 1 to {} characters.", MAX_NAME_LEN),
+            ));
+        }
+
+        if self.dice.len() > MAX_SET_DICE
+            || self
+                .dice
+                .iter()
+                .any(|die| die.roll.chars().count() > MAX_ROLL_LEN)
+        {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_set",
+                format!(
+                    "Sets hold at most {} dice, with rolls of at most {} characters.",
+                    MAX_SET_DICE, MAX_ROLL_LEN
+                ),
+            ));
+        }
+
+        // Sets are shared, so their rolls are checked as they are saved
+        // rather than when someone rolls them.
+        for die in &self.dice {
+            let (times, dice) = metrics::parse(&die.roll)?;
+            limits.check(times, &dice)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Every dice set, kept in a JSON file in the data directory.
+#[derive(Debug)]
+pub struct Sets {
+    path: PathBuf,
+    sets: Mutex<BTreeMap<String, DiceSet>>,
+}
+
+impl Sets {
+    /// Loads the sets in a data directory, creating it if need be.
+    pub fn open(dir: &Path) -> Result<Sets, SetsError> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(SETS_FILE);
+        let sets = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Sets {
+            path,
+            sets: Mutex::new(sets),
+        })
+    }
+
+    /// The sets a user owns, by name.
+    pub fn owned(&self, owner: &str) -> Vec<DiceSet> {
+        let mut owned = self
+            .sets
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|set| set.owner == owner)
+            .cloned()
+            .collect::<Vec<_>>();
+        owned.sort_by(|a, b| a.name.cmp(&b.name));
+
+        owned
+    }
+
+    pub fn get(&self, id: &str) -> Result<DiceSet, ApiError> {
+        self.sets
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(unknown_set)
+    }
+
+    pub fn create(
+        &self,
+        owner: &str,
+        req: SetRequest,
+        limits: &Limits,
+    ) -> Result<DiceSet, ApiError> {
+        req.check(limits)?;
+
+        let mut sets = self.sets.lock().unwrap();
+        if sets.values().filter(|set| set.owner == owner).count() >= MAX_SETS_PER_USER {
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "too_many_sets",
+                format!("Accounts may have at most {} sets.", MAX_SETS_PER_USER),
+            ));
+        }
+
+        let id = loop {
+            let id = random_id();
+            if !sets.contains_key(&id) {
+                break id;
+            }
+        };
+
+        let set = DiceSet {
+            id: id.clone(),
+            name: req.name,
+            owner: owner.to_string(),
+            dice: req.dice,
+        };
+        sets.insert(id, set.clone());
+        self.save(&sets)?;
+
+        Ok(set)
+    }
+
+    pub fn update(
+        &self,
+        owner: &str,
+        id: &str,
+        req: SetRequest,
+        limits: &Limits,
+    ) -> Result<DiceSet, ApiError> {
+        req.check(limits)?;
+
+        let mut sets = self.sets.lock().unwrap();
+        let set = owned_mut(&mut sets, owner, id)?;
+        set.name = req.name;
+        set.dice = req.dice;
+
+        let set = set.clone();
+        self.save(&sets)?;
+
+        Ok(set)
+    }
+
+    pub fn delete(&self, owner: &str, id: &str) -> Result<(), ApiError> {
+        let mut sets = self.sets.lock().unwrap();
+        owned_mut(&mut sets, owner, id)?;
+        sets.remove(id);
+        self.save(&sets)?;
+
+        Ok(())
+    }
+
+    /// Writes every set out, through a temporary file so that a crash
+    /// can't leave the file half written.
+    fn save(&self, sets: &BTreeMap<String, DiceSet>) -> Result<(), SetsError> {
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(sets)?)?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+fn owned_mut<'a>(
+    sets: &'a mut BTreeMap<String, DiceSet>,
+    owner: &str,
+    id: &str,
+) -> Result<&'a mut DiceSet, ApiError> {
+    match sets.get_mut(id) {
+        Some(set) if set.owner == owner => Ok(set),
+        Some(_) => Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "not_owner",
+            "Only the set's owner may change it.",
+        )),
+        None => Err(unknown_set()),
+    }
+}
+
+fn unknown_set() -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "unknown_set",
+        "There is no such set.",
+    )
+}
+
+/// A short id for share links, of letters and digits.
+fn random_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(ID_LEN)
+        .collect()
+}
+
+/// `GET/POST /sets` and `GET/PUT/DELETE /sets/{id}`. Reading a set by its
+/// id is open to anyone; the rest needs a session. Changes rewrite the
+/// sets file, so they are made on a blocking thread.
+pub fn routes(
+    sets: Arc<Sets>,
+    accounts: Arc<Accounts>,
+    limits: Limits,
+    body_limit: u64,
+) -> BoxedFilter<(Response,)> {
+    let with_limits = warp::any().map(move || limits);
+    let with_sets = warp::any().map(move || sets.clone());
+    // Finds the user behind the session cookie, or fails with 401.
+    let user = warp::cookie::optional(SESSION_COOKIE).and_then(move |token: Option<String>| {
+        let user = accounts.user(token.as_deref(), Instant::now());
+        async move { user.for_warp() }
+    });
+    let body = warp::body::content_length_limit(body_limit).and(warp::body::json());
+
+    let list = warp::filters::method::get()
+        .and(warp::path("sets"))
+        .and(warp::path::end())
+        .and(user.clone())
+        .and(with_sets.clone())
+        .map(|user: String, sets: Arc<Sets>| warp::reply::json(&sets.owned(&user)).into_response());
+
+    let create = warp::filters::method::post()
+        .and(warp::path("sets"))
+        .and(warp::path::end())
+        .and(user.clone())
+        .and(body)
+        .and(with_sets.clone())
+        .and(with_limits)
+        .and_then(
+            |user: String, req: SetRequest, sets: Arc<Sets>, limits: Limits| async move {
+                blocking(move || sets.create(&user, req, &limits))
+                    .await
+                    .map(|set| {
+                        let reply = warp::reply::json(&set);
+                        warp::reply::with_status(reply, StatusCode::CREATED).into_response()
+                    })
+                    .for_warp()
+            },
+        );
+
+    let get = warp::filters::method::get()
+        .and(warp::path!("sets" / String))
+        .and(with_sets.clone())
+        .and_then(|id: String, sets: Arc<Sets>| async move {
+            sets.get(&id)
+                .map(|set| warp::reply::json(&set).into_response())
+                .for_warp()
+        });
+
+    let update = warp::filters::method::put()
+        .and(warp::path!("sets" / String))
+        .and(user.clone())
+        .and(body)
+        .and(with_sets.clone())
+        .and(with_limits)
+        .and_then(
+            |id: String,
+             user: String,
+             req: SetRequest,
+             sets: Arc<Sets>,
+             limits: Limits| async move {
+                blocking(move || sets.update(&user, &id, req, &limits))
+                    .await
+                    .map(|set| warp::reply::json(&set).into_response())
+                    .for_warp()
+            },
+        );
+
+    let delete = warp::filters::method::delete()
+        .and(warp::path!("sets" / String))
+        .and(user)
+        .and(with_sets)
+        .and_then(|id: String, user: String, sets: Arc<Sets>| async move {
+            blocking(move || sets.delete(&user, &id))
+                .await
+                .map(|()| StatusCode::NO_CONTENT.into_response())
+                .for_warp()
+        });
+
+    list.or(create)
+        .unify()
+        .or(get)
+        .unify()
+        .or(update)
+        .unify()
+        .or(delete)
+        .unify()
+        .boxed()
+}
+
+#[derive(Debug, Error)]
+pub enum SetsError {
+    #[error("Could not access sets: {0}")]
+    Io(#[from] io::Error),
+    #[error("Could not encode sets: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: &str, dice: &[(&str, &str)]) -> SetRequest {
+        SetRequest {
+            name: name.to_string(),
+            dice: dice
+                .iter()
+                .map(|&(name, roll)| SetDie {
+                    name: name.to_string(),
+                    roll: roll.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn sets_crud() {
+        let dir = tempfile::tempdir().unwrap();
+        let sets = Sets::open(dir.path()).unwrap();
+        let limits = Limits::default();
+
+        let set = sets
+            .create("ann", request("Fighter", &[("Attack", "1d20+5")]), &limits)
+            .unwrap();
+        assert_eq!(ID_LEN, set.id.len());
+        assert_eq!(set, sets.get(&set.id).unwrap());
+
+        let error = sets
+            .update("bo", &set.id, request("Mine", &[]), &limits)
+            .unwrap_err();
+        assert_eq!("not_owner", error.body.code);
+
+        let updated = sets
+            .update(
+                "ann",
+                &set.id,
+                request("Fighter 2", &[("Damage", "2d6")]),
+                &limits,
+            )
+            .unwrap();
+        assert_eq!("Fighter 2", updated.name);
+
+        drop(sets);
+        let sets = Sets::open(dir.path()).unwrap();
+        assert_eq!(vec![updated], sets.owned("ann"));
+        assert!(sets.owned("bo").is_empty());
+
+        assert_eq!(
+            "not_owner",
+            sets.delete("bo", &set.id).unwrap_err().body.code
+        );
+        sets.delete("ann", &set.id).unwrap();
+        assert_eq!("unknown_set", sets.get(&set.id).unwrap_err().body.code);
+    }
+
+    #[test]
+    fn sets_check_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let sets = Sets::open(dir.path()).unwrap();
+        let limits = Limits::default();
+
+        let error = sets.create("ann", request(" ", &[]), &limits).unwrap_err();
+        assert_eq!("invalid_name", error.body.code);
+
+        let error = sets
+            .create("ann", request("Set", &[("Die", "1d")]), &limits)
+            .unwrap_err();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, error.status);
+        let error = sets
+            .create("ann", request("Set", &[("Die", "1d0")]), &limits)
+            .unwrap_err();
+        assert_eq!("invalid_range", error.body.code);
+        let error = sets
+            .create("ann", request("Set", &[("Die", "2000000d6")]), &limits)
+            .unwrap_err();
+        assert_eq!("limit_exceeded", error.body.code);
+
+        let long = "1".repeat(MAX_ROLL_LEN + 1);
+        let error = sets
+            .create("ann", request("Set", &[("Die", &long)]), &limits)
+            .unwrap_err();
+        assert_eq!("invalid_set", error.body.code);
+    }
+}
@@ -0,0 +1,310 @@
+use futures::StreamExt;
+use hyper::server::accept;
+use hyper::service::{make_service_fn, Service};
+use hyper::{Body, Request};
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use warp::filters::BoxedFilter;
+use warp::http::header::LOCATION;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::listen::with_remote;
+
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How long a client may take to finish its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many finished handshakes may wait for the server to take them.
+const ACCEPT_BACKLOG: usize = 128;
+
+/// The certificate served, which can be swapped for a new one from the
+/// same files while the server runs.
+pub struct Certs {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<CertifiedKey>,
+}
+
+impl Certs {
+    /// Reads a PEM certificate chain, and a PEM private key in PKCS#8 or
+    /// RSA form.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Certs, TlsError> {
+        Ok(Certs {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(read(cert_path, key_path)?),
+        })
+    }
+
+    /// Reads the files again. The old certificate is kept if they can't
+    /// be read.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let certified = read(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = certified;
+
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for Certs {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn read(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let invalid_cert = || TlsError::InvalidCert(cert_path.to_path_buf());
+    let invalid_key = || TlsError::InvalidKey(key_path.to_path_buf());
+
+    let certs =
+        pemfile::certs(&mut BufReader::new(File::open(cert_path)?)).map_err(|()| invalid_cert())?;
+    if certs.is_empty() {
+        return Err(invalid_cert());
+    }
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|()| invalid_key())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|()| invalid_key())?;
+    }
+    let key = keys.first().ok_or_else(invalid_key)?;
+    let key = sign::any_supported_type(key).map_err(|()| invalid_key())?;
+
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// Reloads the certificate on every SIGHUP, for as long as the server runs.
+pub async fn reload_on_hangup(certs: Arc<Certs>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup()).expect("SIGHUP can be handled");
+
+        while hangup.recv().await.is_some() {
+            match certs.reload() {
+                Ok(()) => log::info!("Reloaded the TLS certificate."),
+                Err(e) => log::error!("Kept the old TLS certificate: {}", e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = certs;
+    }
+}
+
+/// Serves HTTPS until `signal` resolves, then finishes the requests it
+/// has, like warp's `bind_with_graceful_shutdown`.
+pub async fn serve<S>(
+    service: S,
+    listener: TcpListener,
+    certs: Arc<Certs>,
+    signal: impl Future<Output = ()>,
+) -> Result<(), TlsError>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = certs;
+    // Browsers only open WebSockets over HTTP/1.1.
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+
+    let streams = handshakes(listener, TlsAcceptor::from(Arc::new(config)));
+
+    let make_service = make_service_fn(move |stream: &TlsStream<TcpStream>| {
+        let service = with_remote(service.clone(), stream.get_ref().0.peer_addr().ok());
+        async move { Ok::<_, Infallible>(service) }
+    });
+
+    hyper::Server::builder(accept::from_stream(streams.map(Ok::<_, io::Error>)))
+        .serve(make_service)
+        .with_graceful_shutdown(signal)
+        .await?;
+
+    Ok(())
+}
+
+/// Accepts connections and finishes their handshakes apart, so that slow
+/// clients don't hold up the rest.
+fn handshakes(
+    mut listener: TcpListener,
+    acceptor: TlsAcceptor,
+) -> mpsc::Receiver<TlsStream<TcpStream>> {
+    let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Could not accept a connection: {}", e);
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let mut tx = tx.clone();
+
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    // Fails only once the server has stopped.
+                    Ok(Ok(stream)) => drop(tx.send(stream).await),
+                    Ok(Err(e)) => log::debug!("TLS handshake with {} failed: {}", remote, e),
+                    Err(_) => log::debug!("TLS handshake with {} timed out", remote),
+                }
+            });
+        }
+    });
+
+    rx
+}
+
+/// Sends plain HTTP requests to the same path over HTTPS.
+pub fn redirect(https_port: u16) -> BoxedFilter<(Response,)> {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::<String>("host"))
+        .map(
+            move |path: warp::path::FullPath, query: String, host: String| {
+                let mut location = https_url(&host, https_port, path.as_str());
+                if !query.is_empty() {
+                    location.push('?');
+                    location.push_str(&query);
+                }
+
+                warp::reply::with_header(StatusCode::PERMANENT_REDIRECT, LOCATION, location)
+                    .into_response()
+            },
+        )
+        .boxed()
+}
+
+/// The HTTPS URL of a path on a host, which may have been given with the
+/// plain HTTP port.
+fn https_url(host: &str, port: u16, path: &str) -> String {
+    let name = match host.find(']') {
+        // An IPv6 address, such as `[::1]:80`.
+        Some(end) if host.starts_with('[') => &host[..=end],
+        _ => host.split(':').next().unwrap_or(host),
+    };
+
+    if port == 443 {
+        format!("https://{}{}", name, path)
+    } else {
+        format!("https://{}:{}{}", name, port, path)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Could not serve TLS: {0}")]
+    Io(#[from] io::Error),
+    #[error("HTTPS server failed: {0}")]
+    Server(#[from] hyper::Error),
+    #[error("Could not redirect HTTP on {0}: {1}")]
+    Redirect(SocketAddr, #[source] warp::Error),
+    #[error("No valid PEM certificates in {0}")]
+    InvalidCert(PathBuf),
+    #[error("No valid PEM PKCS#8 or RSA private key in {0}")]
+    InvalidKey(PathBuf),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    fn write_cert(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+
+        fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        (cert_path, key_path)
+    }
+
+    fn served(certs: &Certs) -> Vec<u8> {
+        certs.current.read().unwrap().cert[0].0.clone()
+    }
+
+    #[test]
+    fn certs_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = write_cert(dir.path(), "a.local");
+        let certs = Certs::load(&cert_path, &key_path).unwrap();
+        let first = served(&certs);
+
+        write_cert(dir.path(), "b.local");
+        certs.reload().unwrap();
+        let second = served(&certs);
+        assert_ne!(first, second);
+
+        fs::write(&key_path, "not a key").unwrap();
+        assert!(matches!(certs.reload(), Err(TlsError::InvalidKey(_))));
+        assert_eq!(second, served(&certs));
+    }
+
+    #[test]
+    fn certs_need_both_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = write_cert(dir.path(), "a.local");
+
+        fs::write(&cert_path, "").unwrap();
+        assert!(matches!(
+            Certs::load(&cert_path, &key_path),
+            Err(TlsError::InvalidCert(_))
+        ));
+        assert!(matches!(
+            Certs::load(&dir.path().join("missing.pem"), &key_path),
+            Err(TlsError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn https_url_swaps_port() {
+        assert_eq!(
+            "https://dice.local/roll/1d6",
+            https_url("dice.local", 443, "/roll/1d6")
+        );
+        assert_eq!(
+            "https://dice.local:3443/",
+            https_url("dice.local:3000", 3443, "/")
+        );
+        assert_eq!("https://[::1]:3443/", https_url("[::1]:3000", 3443, "/"));
+    }
+
+    #[tokio::test]
+    async fn redirect_keeps_path_and_query() {
+        let response = warp::test::request()
+            .path("/roll?e=1d20")
+            .header("host", "192.168.1.5:3000")
+            .reply(&redirect(3443))
+            .await;
+
+        assert_eq!(StatusCode::PERMANENT_REDIRECT, response.status());
+        assert_eq!(
+            "https://192.168.1.5:3443/roll?e=1d20",
+            response.headers()["location"]
+        );
+    }
+}